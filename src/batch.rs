@@ -0,0 +1,51 @@
+use bitcoincore_rpc::{jsonrpc, Client, Error, RpcApi};
+use serde::de::DeserializeOwned;
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::Value;
+
+///Calls `method` once for each of `params` in a single JSON-RPC batch request. bitcoind runs
+///the calls in order. Returns the result of each call, or an error if the request failed.
+pub fn call_batch<T: DeserializeOwned>(
+    client: &Client,
+    method: &str,
+    params: &[Vec<Value>],
+) -> Result<Vec<Result<T, Error>>, Error> {
+    match params {
+        [] => return Ok(vec![]),
+        //A transport error fails the request, as it does for a batch
+        [params] => {
+            return match client.call(method, params) {
+                Err(Error::JsonRpc(jsonrpc::Error::Transport(err))) => {
+                    Err(Error::JsonRpc(jsonrpc::Error::Transport(err)))
+                }
+                res => Ok(vec![res]),
+            }
+        }
+        _ => {}
+    }
+    let raw_params = params
+        .iter()
+        .map(|params| {
+            params
+                .iter()
+                .map(|param| to_raw_value(param).map_err(Error::Json))
+                .collect::<Result<Vec<Box<RawValue>>, Error>>()
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let jsonrpc_client = client.get_jsonrpc_client();
+    let requests: Vec<jsonrpc::Request> = raw_params
+        .iter()
+        .map(|params| jsonrpc_client.build_request(method, params))
+        .collect();
+    let responses = jsonrpc_client.send_batch(&requests)?;
+    Ok(responses
+        .into_iter()
+        .map(|response| match response {
+            Some(response) => response.result().map_err(Error::from),
+            None => Err(Error::ReturnedError(format!(
+                "No {} response in batch",
+                method
+            ))),
+        })
+        .collect())
+}
@@ -0,0 +1,192 @@
+use crate::config::Config;
+use crate::TxDepth;
+use anyhow::{bail, Context, Result};
+use bitcoincore_rpc::bitcoin::Txid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+//First line of a state file, followed by a line with the txid of each tx sent.
+#[derive(Debug, Serialize, Deserialize)]
+struct Plan<T> {
+    //A copy is only resumed with the same nodes
+    sources: Vec<String>,
+    destinations: Vec<String>,
+    txs: T,
+}
+
+///Progress of an rpc copy, written into --state-file as txs are accepted by every destination.
+pub struct Checkpoint {
+    file: Mutex<File>,
+}
+
+impl Checkpoint {
+    ///Starts a state file with the txs to copy.
+    pub fn create(path: &Path, txs: &[TxDepth], cfg: &Config) -> Result<Self> {
+        let (sources, destinations) = node_urls(cfg);
+        let plan = Plan {
+            sources,
+            destinations,
+            txs,
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, &plan)?;
+        writeln!(writer)?;
+        let file = writer.into_inner()?;
+        file.sync_all()?;
+        Ok(Checkpoint {
+            file: Mutex::new(file),
+        })
+    }
+
+    ///Continues a state file. Returns it with the txs not sent yet, and the number of txs sent.
+    pub fn resume(path: &Path, cfg: &Config) -> Result<(Self, Vec<TxDepth>, usize)> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let plan: Plan<Vec<TxDepth>> =
+            serde_json::from_str(&lines.next().context("Empty state file")??)?;
+        let (sources, destinations) = node_urls(cfg);
+        if plan.sources != sources || plan.destinations != destinations {
+            bail!(
+                "State file written for sources {:?} and destinations {:?}",
+                plan.sources,
+                plan.destinations
+            );
+        }
+        //A line cut by a crash is not a txid
+        let sent: HashSet<Txid> = lines
+            .map_while(|line| line.ok())
+            .filter_map(|line| line.parse().ok())
+            .collect();
+        let pending = plan
+            .txs
+            .into_iter()
+            .filter(|tx| !sent.contains(&tx.tx_id))
+            .collect();
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        //Ends a cut line
+        writeln!(file)?;
+        Ok((
+            Checkpoint {
+                file: Mutex::new(file),
+            },
+            pending,
+            sent.len(),
+        ))
+    }
+
+    ///Writes the txs accepted by every destination.
+    pub fn record(&self, txids: &[Txid]) -> Result<()> {
+        if txids.is_empty() {
+            return Ok(());
+        }
+        let lines: String = txids.iter().map(|txid| format!("{}\n", txid)).collect();
+        self.file.lock().unwrap().write_all(lines.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn node_urls(cfg: &Config) -> (Vec<String>, Vec<String>) {
+    let sources = std::iter::once(cfg.source_ip_addr.clone())
+        .chain(cfg.extra_source.iter().map(|source| source.url.clone()))
+        .collect();
+    let destinations = cfg
+        .destinations()
+        .into_iter()
+        .map(|dest| dest.url)
+        .collect();
+    (sources, destinations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    fn tx(n: u8) -> TxDepth {
+        TxDepth {
+            ancestor_count: n as usize,
+            tx_id: Txid::from_byte_array([n; 32]),
+            depends: vec![Txid::from_byte_array([n + 100; 32])],
+            vsize: 100 + n as u64,
+            fee: Some(1_000 + n as u64),
+        }
+    }
+
+    fn state_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "mempoolcp-test-{}-{}.state",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn resumes_plan_and_sent_txids() {
+        let path = state_file("roundtrip");
+        let cfg = Config::default();
+        let txs = vec![tx(1), tx(2), tx(3)];
+        let checkpoint = Checkpoint::create(&path, &txs, &cfg).unwrap();
+        checkpoint.record(&[txs[0].tx_id, txs[2].tx_id]).unwrap();
+        drop(checkpoint);
+
+        let (_, pending, sent) = Checkpoint::resume(&path, &cfg).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(sent, 2);
+        assert_eq!(pending.len(), 1);
+        let pending = &pending[0];
+        assert_eq!(pending.tx_id, txs[1].tx_id);
+        assert_eq!(pending.ancestor_count, txs[1].ancestor_count);
+        assert_eq!(pending.depends, txs[1].depends);
+        assert_eq!(pending.vsize, txs[1].vsize);
+        assert_eq!(pending.fee, txs[1].fee);
+    }
+
+    #[test]
+    fn resumes_with_last_txid_cut_off() {
+        let path = state_file("cut");
+        let cfg = Config::default();
+        let txs = vec![tx(1), tx(2), tx(3)];
+        let checkpoint = Checkpoint::create(&path, &txs, &cfg).unwrap();
+        checkpoint.record(&[txs[0].tx_id]).unwrap();
+        drop(checkpoint);
+        //A crash while writing the second txid
+        let cut = txs[1].tx_id.to_string();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&cut.as_bytes()[..20])
+            .unwrap();
+
+        let (checkpoint, pending, sent) = Checkpoint::resume(&path, &cfg).unwrap();
+        assert_eq!(sent, 1);
+        let pending: Vec<Txid> = pending.iter().map(|tx| tx.tx_id).collect();
+        assert_eq!(pending, vec![txs[1].tx_id, txs[2].tx_id]);
+
+        //Txids recorded after resuming are not glued to the cut line
+        checkpoint.record(&[txs[1].tx_id]).unwrap();
+        drop(checkpoint);
+        let (_, pending, sent) = Checkpoint::resume(&path, &cfg).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sent, 2);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].tx_id, txs[2].tx_id);
+    }
+
+    #[test]
+    fn rejects_state_file_of_other_nodes() {
+        let path = state_file("nodes");
+        let cfg = Config::default();
+        drop(Checkpoint::create(&path, &[tx(1)], &cfg).unwrap());
+
+        let mut other = Config::default();
+        other.dest_ip_addr = "10.0.0.9".to_string();
+        let result = Checkpoint::resume(&path, &other);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
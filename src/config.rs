@@ -2,16 +2,18 @@ extern crate serde;
 
 use std::path::PathBuf;
 
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use std::fmt;
+use std::net::SocketAddr;
 use url::Url;
 
 #[derive(Debug, Serialize, Deserialize, Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[serde(default)]
 pub struct Config {
     ///Source bitcoind node rpc url i.e. http://localhost, '.' for source_url defined in config
     ///file
@@ -46,18 +48,87 @@ pub struct Config {
     ///Port for destination bitcoin node rpc, use only to override --net network defaults
     #[arg(short = 'P', long, requires = "dest")]
     pub dest_port: Option<u16>,
+    ///Maximum concurrent sends to destination node, number of cpus by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub dest_concurrency: Option<u64>,
     ///Bitcoin network type. Sets rpc port default.
     #[arg(short = 't',long,default_value_t=Net::MainNet, value_enum)]
     pub net: Net,
     ///ZMQ Interface to receive tx while working and send all at the end.
     #[arg(short = 'z', long, requires = "dest")]
     pub zmq_address: Option<Url>,
+    ///Use the ZMQ interface of source node, found with getzmqnotifications rpc if --zmq-address
+    ///is not set
+    #[arg(long, default_value_t = false)]
+    pub zmq: bool,
+    ///ZMQ interface publishing raw txs (zmqpubrawtx), if not the same as --zmq-address. Txs
+    ///received from it are not asked to source node
+    #[arg(long, requires = "zmq_address")]
+    pub zmq_rawtx_address: Option<Url>,
+    ///Seconds to wait for the connection to the ZMQ publisher
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub zmq_timeout: u64,
     ///Use get_raw_mempool_verbose rpc call which is faster but consumes a lot of mememory.
     #[arg(short, long, default_value_t = false)]
     pub fast_mode: bool,
+    ///Rpc calls sent together in a single JSON-RPC batch request: mempool entries, raw txs and
+    ///sends of txs ready at the same time
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub batch_size: u64,
+    ///Times an rpc call is tried again when the node can't be reached, is warming up or is
+    ///busy. Waits grow exponentially between tries
+    #[arg(long, default_value_t = 5)]
+    pub max_retries: u32,
+    ///Keep running after the initial copy, forwarding ZMQ transactions to destination as they
+    ///arrive. Implies --zmq
+    #[arg(long, default_value_t = false)]
+    pub sync: bool,
+    ///Seconds between full mempool reconciliation passes in sync mode
+    #[arg(long, default_value_t = 600, value_parser = clap::value_parser!(u64).range(1..))]
+    pub reconcile_interval: u64,
+    ///Write source mempool into a bitcoind mempool.dat file instead of sending it to destination
+    ///(destination is not used)
+    #[arg(long, conflicts_with = "sync")]
+    pub to_mempool_dat: Option<PathBuf>,
+    ///Write the old non obfuscated mempool.dat format (version 1) with --to-mempool-dat
+    #[arg(long, default_value_t = false, requires = "to_mempool_dat")]
+    pub mempool_dat_v1: bool,
+    ///Do not send anything, check with testmempoolaccept what destination would do with each tx
+    #[arg(long, default_value_t = false, conflicts_with_all = ["sync", "to_mempool_dat"])]
+    pub dry_run: bool,
+    ///Read transactions from a bitcoind mempool.dat file instead of from source node (source is
+    ///not used)
+    #[arg(long, conflicts_with_all = ["sync", "to_mempool_dat"])]
+    pub from_mempool_dat: Option<PathBuf>,
+    ///Write the txs to copy and the ones already sent into this file while copying, so an
+    ///interrupted copy can be resumed with --resume
+    #[arg(long, conflicts_with_all = ["sync", "dry_run", "to_mempool_dat", "from_mempool_dat"])]
+    pub state_file: Option<PathBuf>,
+    ///Continue the copy in --state-file instead of starting a new one. Txs already sent are
+    ///skipped, the rest are checked against destination again
+    #[arg(long, default_value_t = false, requires = "state_file")]
+    pub resume: bool,
+    ///Print a summary of the copy in the given format at the end
+    #[arg(long, value_enum, conflicts_with_all = ["sync", "to_mempool_dat", "from_mempool_dat"])]
+    pub report: Option<ReportFormat>,
+    ///Write the report into a file instead of stdout
+    #[arg(long, requires = "report")]
+    pub report_file: Option<PathBuf>,
+    ///Serve Prometheus metrics on http://<ADDRESS>/metrics i.e. 127.0.0.1:9332
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
     ///Show effective configuration
     #[arg(short, long)]
     pub verbose: bool,
+    //Last fields written, toml needs arrays of tables after plain values.
+    ///Also copy the txs of this node, can be repeated. Mempools are merged, conflicting txs are
+    ///solved by mining score. Format: URL[,cookie=PATH][,user=USER][,passwd=PASSWD]
+    #[arg(long, value_parser = NodeConfig::parse_source, conflicts_with_all = ["sync", "to_mempool_dat", "from_mempool_dat"])]
+    pub extra_source: Vec<NodeConfig>,
+    ///Also send the txs to this node, can be repeated. Format: URL[,cookie=PATH][,user=USER]
+    ///[,passwd=PASSWD][,concurrency=N] i.e. http://node2:8332,cookie=/node2/.cookie
+    #[arg(long, value_parser = NodeConfig::parse_dest, conflicts_with_all = ["to_mempool_dat", "dry_run"])]
+    pub extra_dest: Vec<NodeConfig>,
 
     ///Use config in ~/.config/default-config.toml If file do not exists create it with current params
     #[arg(short = 'c', long, group = "file")]
@@ -67,32 +138,71 @@ pub struct Config {
     #[arg(short = 'C', long, group = "file")]
     #[serde(skip)]
     use_config_path: Option<PathBuf>,
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
 }
 
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Effective configuration:\n")?;
-        write!(f, "  Source ip: {}\n", &self.source_ip_addr)?;
-        write!(f, "  Source port: {:?}\n", &self.source_port.unwrap())?;
-        write!(f, "  Source user name: ****\n")?;
-        write!(f, "  Source password: ****\n")?;
-        write!(f, "  Destination ip: {}\n", &self.dest_ip_addr)?;
-        write!(f, "  Destination port: {:?}\n", &self.dest_port.unwrap())?;
-        write!(f, "  Destination user name: ****\n")?;
-        write!(f, "  Destination password: ****\n")?;
+        writeln!(f, "Effective configuration:")?;
+        writeln!(f, "  Source ip: {}", &self.source_ip_addr)?;
+        writeln!(f, "  Source port: {:?}", &self.source_port.unwrap())?;
+        writeln!(f, "  Source user name: ****")?;
+        writeln!(f, "  Source password: ****")?;
+        for source in &self.extra_source {
+            writeln!(f, "  Extra source: {}", source.url)?;
+        }
+        writeln!(f, "  Destination ip: {}", &self.dest_ip_addr)?;
+        writeln!(f, "  Destination port: {:?}", &self.dest_port.unwrap())?;
+        writeln!(f, "  Destination user name: ****")?;
+        writeln!(f, "  Destination password: ****")?;
+        writeln!(f, "  Destination concurrency: {:?}", &self.dest_concurrency)?;
+        for dest in &self.extra_dest {
+            writeln!(
+                f,
+                "  Extra destination: {} (concurrency: {:?})",
+                dest.url, dest.concurrency
+            )?;
+        }
         write!(f, "  Source cookie auth path: ")?;
         print_pathbuffer(f, &self.source_cookie_auth_path)?;
         write!(f, "  Dest cookie auth path: ")?;
         print_pathbuffer(f, &self.dest_cookie_auth_path)?;
-        write!(f, "\n  Network: {:?}\n", &self.net)?;
+        writeln!(f, "\n  Network: {:?}", &self.net)?;
         write!(f, "  ZMQ Address: ")?;
         match &self.zmq_address {
             Some(address) => write!(f, "{:?}", address.as_ref().to_string())?,
             None => write!(f, "None")?,
         }
-        write!(f, "\n  Fast Mode: {:?}\n", &self.fast_mode)?;
-        write!(f, "  Verbose: {:?}\n", &self.verbose)?;
-        write!(f, "  Config file used: {:?}\n", &self.config_file_used())?;
+        write!(f, "\n  ZMQ rawtx Address: ")?;
+        match &self.zmq_rawtx_address {
+            Some(address) => write!(f, "{:?}", address.as_ref().to_string())?,
+            None => write!(f, "None")?,
+        }
+        writeln!(f, "\n  ZMQ timeout: {:?}s", &self.zmq_timeout)?;
+        writeln!(f, "  ZMQ: {:?}", &self.zmq)?;
+        writeln!(f, "  Fast Mode: {:?}", &self.fast_mode)?;
+        writeln!(f, "  Batch size: {:?}", &self.batch_size)?;
+        writeln!(f, "  Max retries: {:?}", &self.max_retries)?;
+        writeln!(f, "  Sync Mode: {:?}", &self.sync)?;
+        writeln!(f, "  Reconcile interval: {:?}s", &self.reconcile_interval)?;
+        write!(f, "  Export to mempool.dat: ")?;
+        print_pathbuffer(f, &self.to_mempool_dat)?;
+        writeln!(f, "  Mempool.dat v1 format: {:?}", &self.mempool_dat_v1)?;
+        writeln!(f, "  Dry run: {:?}", &self.dry_run)?;
+        write!(f, "  Import from mempool.dat: ")?;
+        print_pathbuffer(f, &self.from_mempool_dat)?;
+        write!(f, "  State file: ")?;
+        print_pathbuffer(f, &self.state_file)?;
+        writeln!(f, "  Resume: {:?}", &self.resume)?;
+        writeln!(f, "  Report: {:?}", &self.report)?;
+        write!(f, "  Report file: ")?;
+        print_pathbuffer(f, &self.report_file)?;
+        writeln!(f, "  Metrics listen address: {:?}", &self.metrics_listen)?;
+        writeln!(f, "  Verbose: {:?}", &self.verbose)?;
+        writeln!(f, "  Command: {:?}", &self.command)?;
+        writeln!(f, "  Config file used: {:?}", &self.config_file_used())?;
         Ok(())
     }
 }
@@ -108,19 +218,40 @@ impl std::default::Default for Config {
             dest_user: None,
             dest_passwd: None,
             dest_port: None,
+            dest_concurrency: None,
+            extra_source: vec![],
+            extra_dest: vec![],
             source_cookie_auth_path: None,
             dest_cookie_auth_path: None,
             net: Net::MainNet,
-            zmq_address: Url::parse("tcp://127.0.0.1:29000").ok(),
+            zmq_address: None,
+            zmq: false,
+            zmq_rawtx_address: None,
+            zmq_timeout: 10,
             fast_mode: false,
+            batch_size: 1,
+            max_retries: 5,
+            sync: false,
+            reconcile_interval: 600,
+            to_mempool_dat: None,
+            mempool_dat_v1: false,
+            dry_run: false,
+            from_mempool_dat: None,
+            state_file: None,
+            resume: false,
+            report: None,
+            report_file: None,
+            metrics_listen: None,
             use_config: false,
             use_config_path: None,
+            command: None,
             verbose: false,
         }
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
+#[allow(clippy::enum_variant_names)]
 pub enum Net {
     MainNet = 8332,
     TestNet = 18332,
@@ -128,9 +259,80 @@ pub enum Net {
     RegTest = 18443,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum ReportFormat {
+    Json,
+}
+
+///Instead of copying, written after the options i.e. mempoolcp <SOURCE> <DEST> diff
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    ///Compare source and destination mempools contents, nothing is sent
+    Diff(DiffArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DiffArgs {
+    ///Write the txs only in source or only in destination into a NDJSON file
+    #[arg(long)]
+    pub ndjson: Option<PathBuf>,
+}
+
+///An extra source or destination node. The url includes the rpc port.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeConfig {
+    pub url: String,
+    pub cookie_auth_path: Option<PathBuf>,
+    pub user: Option<String>,
+    pub passwd: Option<String>,
+    ///Maximum concurrent sends to a destination, number of cpus if not set
+    pub concurrency: Option<usize>,
+}
+
+impl NodeConfig {
+    fn parse_source(value: &str) -> Result<Self, String> {
+        Self::parse(value, "source")
+    }
+
+    fn parse_dest(value: &str) -> Result<Self, String> {
+        Self::parse(value, "destination")
+    }
+
+    //Only destinations have concurrency.
+    fn parse(value: &str, kind: &str) -> Result<Self, String> {
+        let mut fields = value.split(',');
+        let mut dest = NodeConfig {
+            url: fields.next().unwrap_or_default().to_string(),
+            cookie_auth_path: None,
+            user: None,
+            passwd: None,
+            concurrency: None,
+        };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("cookie", path)) => dest.cookie_auth_path = Some(PathBuf::from(path)),
+                Some(("user", user)) => dest.user = Some(user.to_string()),
+                Some(("passwd", passwd)) => dest.passwd = Some(passwd.to_string()),
+                Some(("concurrency", concurrency)) if kind == "destination" => {
+                    dest.concurrency = Some(
+                        concurrency
+                            .parse()
+                            .ok()
+                            .filter(|concurrency| *concurrency > 0)
+                            .ok_or(format!("Invalid concurrency: {}", concurrency))?,
+                    )
+                }
+                _ => return Err(format!("Unknown {} option: {}", kind, field)),
+            }
+        }
+        Ok(dest)
+    }
+}
+
 impl Config {
     pub fn load() -> Result<Self> {
         let mut cfg = Config::parse();
+        let command = cfg.command.take();
         if cfg.use_config {
             cfg = confy::load("mempoolcp", None)?;
             cfg.use_config = true;
@@ -141,7 +343,8 @@ impl Config {
             config.use_config_path = cfg.use_config_path;
             cfg = config;
         }
-        if cfg.source_cookie_auth_path.is_none() {
+        //Importing from a file do not need a source node
+        if cfg.source_cookie_auth_path.is_none() && cfg.from_mempool_dat.is_none() {
             if cfg.source_user.is_none() {
                 cfg.source_user = rpassword::prompt_password("Source bitcoind node user: ").ok();
             }
@@ -150,7 +353,8 @@ impl Config {
                     rpassword::prompt_password("Source bitcoind node password: ").ok();
             }
         }
-        if cfg.dest_cookie_auth_path.is_none() {
+        //Exporting to a file do not need a destination node
+        if cfg.dest_cookie_auth_path.is_none() && cfg.to_mempool_dat.is_none() {
             if cfg.dest_user.is_none() {
                 cfg.dest_user = rpassword::prompt_password("Destination bitcoind node user: ").ok();
             }
@@ -159,11 +363,70 @@ impl Config {
                     rpassword::prompt_password("Destination bitcoind node password: ").ok();
             }
         }
+        for node in cfg
+            .extra_source
+            .iter_mut()
+            .chain(cfg.extra_dest.iter_mut())
+            .filter(|node| node.cookie_auth_path.is_none())
+        {
+            if node.user.is_none() {
+                node.user = rpassword::prompt_password(format!("{} user: ", node.url)).ok();
+            }
+            if node.passwd.is_none() {
+                node.passwd = rpassword::prompt_password(format!("{} password: ", node.url)).ok();
+            }
+        }
+        cfg.command = command;
         cfg.source_port = Some(cfg.source_port.unwrap_or(cfg.net as u16));
         cfg.dest_port = Some(cfg.dest_port.unwrap_or(cfg.net as u16));
+        cfg.validate()?;
 
         Ok(cfg)
     }
+
+    //Values read from a config file do not go through clap checks.
+    fn validate(&self) -> Result<()> {
+        if self.batch_size == 0 {
+            bail!("Invalid batch_size: 0, at least one call per batch is needed");
+        }
+        if let Some(dest) = self
+            .destinations()
+            .iter()
+            .find(|dest| dest.concurrency == Some(0))
+        {
+            bail!(
+                "Invalid concurrency of destination {}: 0, at least one send at a time is needed",
+                dest.url
+            );
+        }
+        //Clap can't make an option conflict with a subcommand
+        if matches!(self.command, Some(Command::Diff(_))) && self.report.is_some() {
+            bail!("--report can't be used with diff, it is a summary of a copy");
+        }
+        Ok(())
+    }
+
+    ///Destination node followed by --extra-dest nodes.
+    pub fn destinations(&self) -> Vec<NodeConfig> {
+        let dest = NodeConfig {
+            url: self.dest_ip_addr.clone(),
+            cookie_auth_path: self.dest_cookie_auth_path.clone(),
+            user: self.dest_user.clone(),
+            passwd: self.dest_passwd.clone(),
+            concurrency: self
+                .dest_concurrency
+                .map(|concurrency| concurrency as usize),
+        };
+        std::iter::once(dest)
+            .chain(self.extra_dest.iter().cloned())
+            .collect()
+    }
+
+    ///ZMQ is used with --zmq, --zmq-address or --sync, which needs it.
+    pub fn zmq_enabled(&self) -> bool {
+        self.zmq || self.zmq_address.is_some() || self.sync
+    }
+
     fn config_file_used(&self) -> String {
         if self.use_config {
             "~/.config/default_config".to_string()
@@ -182,10 +445,68 @@ impl Config {
 fn print_pathbuffer(f: &mut fmt::Formatter, path_buff: &Option<PathBuf>) -> Result<(), fmt::Error> {
     match path_buff {
         Some(path) => match path.to_str() {
-            Some(path_str) => write!(f, "{:?}\n", path_str)?,
-            None => write!(f, "None\n")?,
+            Some(path_str) => writeln!(f, "{:?}", path_str)?,
+            None => writeln!(f, "None")?,
         },
-        None => write!(f, "None\n")?,
+        None => writeln!(f, "None")?,
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_without_zmq_address_keeps_zmq_off() {
+        let path = std::env::temp_dir().join(format!("mempoolcp-test-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "source_ip_addr = '10.0.0.1'\ndest_ip_addr = '10.0.0.2'\nnet = 'RegTest'\n",
+        )
+        .unwrap();
+        let cfg: Config = confy::load_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(cfg.source_ip_addr, "10.0.0.1");
+        assert_eq!(cfg.net, Net::RegTest);
+        assert_eq!(cfg.zmq_address, None);
+        assert!(!cfg.zmq_enabled());
+    }
+
+    #[test]
+    fn rejects_zero_batch_size() {
+        let mut cfg = Config::default();
+        assert!(cfg.validate().is_ok());
+        cfg.batch_size = 0;
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_destination_concurrency() {
+        let cfg = Config {
+            dest_concurrency: Some(0),
+            ..Default::default()
+        };
+        assert!(cfg.validate().is_err());
+
+        let mut cfg = Config {
+            extra_dest: vec![NodeConfig::parse_dest("http://node2:8332,concurrency=2").unwrap()],
+            ..Default::default()
+        };
+        assert!(cfg.validate().is_ok());
+        cfg.extra_dest[0].concurrency = Some(0);
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_report_with_diff() {
+        let mut cfg = Config {
+            command: Some(Command::Diff(DiffArgs { ndjson: None })),
+            ..Default::default()
+        };
+        assert!(cfg.validate().is_ok());
+        cfg.report = Some(ReportFormat::Json);
+        assert!(cfg.validate().is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use crate::config::NodeConfig;
+use crate::metrics::Node;
+use crate::node::RpcNode;
+use crate::outcome::OutcomeCounts;
+use bitcoincore_rpc::Client;
+use std::ops::Deref;
+use std::sync::atomic::AtomicBool;
+use std::thread;
+
+///A destination node and what it did with the txs sent to it.
+pub struct Destination {
+    node: RpcNode,
+    pub outcomes: OutcomeCounts,
+    ///Set when the node does not support submitpackage
+    pub no_submitpackage: AtomicBool,
+}
+
+impl Destination {
+    pub fn new(cfg: &NodeConfig, client: Client, max_retries: u32) -> Self {
+        Destination {
+            node: RpcNode::new(
+                Node::Destination,
+                &cfg.url,
+                client,
+                cfg.concurrency.unwrap_or_else(rayon::current_num_threads),
+                max_retries,
+            ),
+            outcomes: OutcomeCounts::new(),
+            no_submitpackage: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Deref for Destination {
+    type Target = RpcNode;
+
+    fn deref(&self) -> &RpcNode {
+        &self.node
+    }
+}
+
+///Runs `send` for every destination at the same time, returns the results in the same order.
+pub fn fan_out<T: Send>(dests: &[Destination], send: impl Fn(&Destination) -> T + Sync) -> Vec<T> {
+    match dests {
+        [dest] => vec![send(dest)],
+        //A thread per destination, the rayon pool would cap them to the number of cpus.
+        _ => thread::scope(|s| {
+            let send = &send;
+            let handles: Vec<_> = dests
+                .iter()
+                .map(|dest| s.spawn(move || send(dest)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        }),
+    }
+}
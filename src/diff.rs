@@ -0,0 +1,144 @@
+use crate::config::{Config, DiffArgs};
+use crate::destination::Destination;
+use crate::source::Source;
+use crate::{get_tx_dept_vec, TxDepth};
+use anyhow::{Context, Result};
+use bitcoincore_rpc::bitcoin::Txid;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+//Lower bounds of the feerate histogram buckets, in sat/vB.
+const FEERATE_BUCKETS: [u64; 12] = [0, 1, 2, 3, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+//Where a tx was found.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Side {
+    Source,
+    Destination,
+}
+
+//A line of the NDJSON file.
+#[derive(Debug, Serialize)]
+struct DiffLine {
+    txid: Txid,
+    only_in: Side,
+    vsize: u64,
+    fee: Option<u64>,
+}
+
+impl DiffLine {
+    fn new(tx: &TxDepth, only_in: Side) -> Self {
+        DiffLine {
+            txid: tx.tx_id,
+            only_in,
+            vsize: tx.vsize,
+            fee: tx.fee,
+        }
+    }
+}
+
+//Totals of the txs of one side of the diff.
+#[derive(Debug, Default)]
+struct DiffTotals {
+    count: usize,
+    vsize: u64,
+    fees: u64,
+    histogram: [usize; FEERATE_BUCKETS.len()],
+}
+
+impl DiffTotals {
+    fn add(&mut self, tx: &TxDepth) {
+        let fee = tx.fee.unwrap_or(0);
+        self.count += 1;
+        self.vsize += tx.vsize;
+        self.fees += fee;
+        let feerate = fee / tx.vsize.max(1);
+        let bucket = FEERATE_BUCKETS.partition_point(|bound| *bound <= feerate) - 1;
+        self.histogram[bucket] += 1;
+    }
+
+    fn print(&self, title: &str) {
+        outln!(
+            "\n{}: {} txs, {} vB, {} sat fees",
+            title,
+            self.count,
+            self.vsize,
+            self.fees
+        );
+        for (i, count) in self.histogram.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            match FEERATE_BUCKETS.get(i + 1) {
+                Some(upper) => outln!("#{}-{} sat/vB: {}", FEERATE_BUCKETS[i], upper, count),
+                None => outln!("#{}+ sat/vB: {}", FEERATE_BUCKETS[i], count),
+            }
+        }
+    }
+}
+
+///Compares source and destination mempools contents by txid. Both mempools are not taken at the
+///same time, txs arriving meanwhile show up as differences.
+pub fn diff_mempools(
+    source: &Source,
+    dest: &Destination,
+    args: &DiffArgs,
+    cfg: &Config,
+) -> Result<()> {
+    let (source_txs, _) =
+        get_tx_dept_vec(source, cfg).context(format!("Can't connect to {}", cfg.source_ip_addr))?;
+    let (dest_txs, _) =
+        get_tx_dept_vec(dest, cfg).context(format!("Can't connect to {}", cfg.dest_ip_addr))?;
+    let dest_txs: HashMap<Txid, TxDepth> = dest_txs
+        .into_iter()
+        .map(|tx_depth| (tx_depth.tx_id, tx_depth))
+        .collect();
+
+    let mut only_source = DiffTotals::default();
+    let mut only_dest = DiffTotals::default();
+    let mut both = DiffTotals::default();
+    let mut lines = vec![];
+    for tx in &source_txs {
+        if dest_txs.contains_key(&tx.tx_id) {
+            both.add(tx);
+        } else {
+            only_source.add(tx);
+            lines.push(DiffLine::new(tx, Side::Source));
+        }
+    }
+    let source_txids: HashSet<&Txid> = source_txs.iter().map(|tx| &tx.tx_id).collect();
+    for tx in dest_txs.values() {
+        if !source_txids.contains(&tx.tx_id) {
+            only_dest.add(tx);
+            lines.push(DiffLine::new(tx, Side::Destination));
+        }
+    }
+
+    outln!(
+        "\n# Transactions in source mempool/destination mempool: {}/{}",
+        source_txs.len(),
+        dest_txs.len()
+    );
+    only_source.print("Only in source");
+    only_dest.print("Only in destination");
+    both.print("In both");
+
+    if let Some(path) = &args.ndjson {
+        write_ndjson(path, &lines).context(format!("Can't write NDJSON file: {:?}", path))?;
+    }
+    Ok(())
+}
+
+fn write_ndjson(path: &Path, lines: &[DiffLine]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for line in lines {
+        serde_json::to_writer(&mut writer, line)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
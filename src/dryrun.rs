@@ -0,0 +1,143 @@
+use crate::config::Config;
+use crate::package::MAX_PACKAGE_COUNT;
+use crate::{get_raw_txs_hex, Stats, TxDepth, TxSource};
+use anyhow::Result;
+use bitcoincore_rpc::bitcoin::Txid;
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+
+//testmempoolaccept result, bitcoincore-rpc one has not package-error and requires allowed.
+#[derive(Debug, Deserialize)]
+struct TestMempoolAcceptResult {
+    txid: Txid,
+    allowed: Option<bool>,
+    #[serde(rename = "reject-reason")]
+    reject_reason: Option<String>,
+    #[serde(rename = "package-error")]
+    package_error: Option<String>,
+}
+
+///Checks txs with testmempoolaccept instead of sending them. As nothing reaches the destination
+///mempool, each tx is tested together with its ancestors not yet in destination. Txs with too
+///many of them for a single testmempoolaccept are not tested.
+pub struct DryRun<'a> {
+    depends: HashMap<Txid, &'a [Txid]>,
+    dest_txids: HashSet<Txid>,
+}
+
+impl<'a> DryRun<'a> {
+    pub fn new(vec: &'a [TxDepth], dest_client: &Client) -> Result<Self> {
+        Ok(DryRun {
+            depends: vec
+                .iter()
+                .map(|tx_depth| (tx_depth.tx_id, &tx_depth.depends[..]))
+                .collect(),
+            dest_txids: dest_client.get_raw_mempool()?.into_iter().collect(),
+        })
+    }
+
+    pub fn test_unit(
+        &self,
+        unit: &[Txid],
+        source_client: &impl TxSource,
+        dest_client: &Client,
+        stats: &Stats,
+        cfg: &Config,
+    ) {
+        let txids = self.with_ancestors(unit);
+        //Splitting them would test children without their parents
+        if txids.len() > MAX_PACKAGE_COUNT {
+            stats.too_large_txs.fetch_add(unit.len(), Ordering::SeqCst);
+            return;
+        }
+        let txids: Vec<&Txid> = txids.iter().collect();
+        let txs_hex = get_raw_txs_hex(&txids, source_client, stats, cfg);
+        //Missing txs are already counted as failed
+        if txs_hex.len() < txids.len() {
+            return;
+        }
+        let txs_hex: Vec<String> = txs_hex.into_iter().map(|(_, tx_hex)| tx_hex).collect();
+
+        let res: Result<Vec<TestMempoolAcceptResult>, _> =
+            dest_client.call("testmempoolaccept", &[txs_hex.into()]);
+        match res {
+            Ok(results) => {
+                for res in results.iter().filter(|res| unit.contains(&res.txid)) {
+                    if res.allowed == Some(true) {
+                        stats.accepted_txs.fetch_add(1, Ordering::SeqCst);
+                    } else {
+                        let reason = res
+                            .reject_reason
+                            .clone()
+                            .or_else(|| res.package_error.clone())
+                            .unwrap_or_else(|| "unknown".to_string());
+                        add_reject_reason(stats, reason, 1);
+                    }
+                }
+            }
+            Err(err) => {
+                let reason = match err {
+                    bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err)) => {
+                        rpc_err.message
+                    }
+                    err => err.to_string(),
+                };
+                add_reject_reason(stats, reason, unit.len());
+            }
+        }
+    }
+
+    //Ancestors of `unit` not in destination, topologically sorted, followed by `unit` itself.
+    fn with_ancestors(&self, unit: &[Txid]) -> Vec<Txid> {
+        let mut txids = vec![];
+        let mut visited: HashSet<Txid> = unit.iter().copied().collect();
+        for txid in unit {
+            self.add_ancestors(txid, &mut visited, &mut txids);
+        }
+        txids.extend(unit);
+        txids
+    }
+
+    fn add_ancestors(&self, txid: &Txid, visited: &mut HashSet<Txid>, txids: &mut Vec<Txid>) {
+        for parent in self.depends.get(txid).copied().unwrap_or_default() {
+            if self.dest_txids.contains(parent) || !visited.insert(*parent) {
+                continue;
+            }
+            self.add_ancestors(parent, visited, txids);
+            txids.push(*parent);
+        }
+    }
+}
+
+fn add_reject_reason(stats: &Stats, reason: String, count: usize) {
+    *stats
+        .reject_reasons
+        .lock()
+        .unwrap()
+        .entry(reason)
+        .or_insert(0) += count;
+}
+
+pub fn print_dry_run_results(stats: &Stats) {
+    let reject_reasons = stats.reject_reasons.lock().unwrap();
+    let mut reasons: Vec<(&String, &usize)> = reject_reasons.iter().collect();
+    reasons.sort_by(|a, b| b.1.cmp(a.1));
+    outln!(
+        "\nDry run: {} txs would be accepted, {} would be rejected",
+        stats.accepted_txs.load(Ordering::SeqCst),
+        reasons.iter().map(|(_, count)| **count).sum::<usize>()
+    );
+    for (reason, count) in reasons {
+        outln!("#{}: {}", reason, count);
+    }
+    let too_large = stats.too_large_txs.load(Ordering::SeqCst);
+    if too_large > 0 {
+        outln!(
+            "{} txs not tested, too large to test together with their ancestors",
+            too_large
+        );
+    }
+}
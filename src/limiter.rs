@@ -0,0 +1,276 @@
+use crate::outcome::SendOutcome;
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::jsonrpc::simple_http;
+use bitcoincore_rpc::Error;
+use rand::Rng;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+//Wait before the first retry, doubled on each one.
+const FIRST_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+///Limits the rpc calls in progress to a node, adjusting to how the node copes with them. The
+///limit is halved, down to 1, each time the node shows it is overloaded: its work queue is full
+///(HTTP 503), it is warming up (-28) or the request times out. Otherwise it grows by one every
+///`limit` requests answered, up to `max`.
+///
+///Calls failing for a reason that could go away (see [`SendOutcome::is_transient`]) are tried
+///again up to `max_retries` times.
+pub struct Limiter {
+    max: usize,
+    max_retries: u32,
+    //Calls tried again
+    retried: AtomicUsize,
+    state: Mutex<LimiterState>,
+    cvar: Condvar,
+}
+
+struct LimiterState {
+    //Fractional, so it grows one call at a time
+    limit: f64,
+    in_flight: usize,
+}
+
+impl Limiter {
+    pub fn new(max: usize, max_retries: u32) -> Self {
+        Limiter {
+            max,
+            max_retries,
+            retried: AtomicUsize::new(0),
+            state: Mutex::new(LimiterState {
+                limit: max as f64,
+                in_flight: 0,
+            }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    ///Calls allowed in progress right now.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit as usize
+    }
+
+    pub fn retried(&self) -> usize {
+        self.retried.load(Ordering::SeqCst)
+    }
+
+    ///Runs `call` once there is room for it. Transient failures are tried again after a growing,
+    ///randomized wait, so calls failing together are not retried together. The last error is
+    ///returned when retries run out.
+    ///
+    ///The caller waits meanwhile, so txs depending on the call result wait for it too.
+    pub fn call<T>(&self, mut call: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+        let mut backoff = FIRST_BACKOFF;
+        let mut retries = 0;
+        loop {
+            let res = self.attempt(&mut call);
+            match &res {
+                Err(err) if SendOutcome::is_transient(err) && retries < self.max_retries => {}
+                _ => return res,
+            }
+            self.back_off(&mut backoff);
+            retries += 1;
+        }
+    }
+
+    ///Like [`Limiter::call`] for a batch of `len` calls, `call` runs the ones at the given indices
+    ///and returns their results. Only the calls failing transiently are tried again, sharing the
+    ///same retries. Once some results are back, a failed retry leaves the last ones.
+    pub fn call_batch<T>(
+        &self,
+        len: usize,
+        mut call: impl FnMut(&[usize]) -> Result<Vec<Result<T, Error>>, Error>,
+    ) -> Result<Vec<Result<T, Error>>, Error> {
+        let mut results: Vec<Option<Result<T, Error>>> = (0..len).map(|_| None).collect();
+        let mut pending: Vec<usize> = (0..len).collect();
+        let mut answered = false;
+        let mut backoff = FIRST_BACKOFF;
+        let mut retries = 0;
+        loop {
+            match self.attempt(|| call(&pending)) {
+                Ok(batch) => {
+                    answered = true;
+                    for (&i, res) in pending.iter().zip(batch) {
+                        results[i] = Some(res);
+                    }
+                    pending.retain(|&i| {
+                        matches!(&results[i], Some(Err(err)) if SendOutcome::is_transient(err))
+                    });
+                }
+                Err(err) if SendOutcome::is_transient(&err) && retries < self.max_retries => {}
+                Err(err) if !answered => return Err(err),
+                Err(_) => break,
+            }
+            if pending.is_empty() || retries >= self.max_retries {
+                break;
+            }
+            self.back_off(&mut backoff);
+            retries += 1;
+        }
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+
+    //Runs `call` once, adjusting the limit to how it went.
+    fn attempt<T>(&self, call: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+        self.acquire();
+        let res = call();
+        self.release(matches!(&res, Err(err) if is_overloaded(err)));
+        res
+    }
+
+    //Waits between half and all of the backoff, then doubles it.
+    fn back_off(&self, backoff: &mut Duration) {
+        thread::sleep(rand::thread_rng().gen_range(*backoff / 2..=*backoff));
+        *backoff = (*backoff * 2).min(MAX_BACKOFF);
+        self.retried.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn acquire(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.in_flight >= state.limit as usize {
+            state = self.cvar.wait(state).unwrap();
+        }
+        state.in_flight += 1;
+    }
+
+    fn release(&self, overloaded: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        if overloaded {
+            state.limit = (state.limit / 2.0).max(1.0);
+        } else {
+            state.limit = (state.limit + 1.0 / state.limit).min(self.max as f64);
+        }
+        drop(state);
+        self.cvar.notify_all();
+    }
+}
+
+//Latency alone is not a sign of overload, some calls are just slower than others.
+fn is_overloaded(err: &Error) -> bool {
+    if let Error::JsonRpc(jsonrpc::Error::Transport(err)) = err {
+        //Timeouts show as WouldBlock on unix
+        if let Some(simple_http::Error::SocketError(err)) = err.downcast_ref::<simple_http::Error>()
+        {
+            return matches!(
+                err.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            );
+        }
+    }
+    matches!(
+        SendOutcome::from_error(err),
+        SendOutcome::Throttled | SendOutcome::Warmup
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn throttled() -> Error {
+        Error::JsonRpc(jsonrpc::Error::Transport(Box::new(
+            simple_http::Error::HttpErrorCode(503),
+        )))
+    }
+
+    fn timed_out() -> Error {
+        Error::JsonRpc(jsonrpc::Error::Transport(Box::new(
+            simple_http::Error::SocketError(io::ErrorKind::TimedOut.into()),
+        )))
+    }
+
+    fn rpc_error(code: i32, message: &str) -> Error {
+        Error::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }))
+    }
+
+    fn warmup() -> Error {
+        rpc_error(-28, "Loading block index...")
+    }
+
+    fn fail(limiter: &Limiter, err: fn() -> Error) {
+        assert!(limiter.call(|| Err::<(), _>(err())).is_err());
+    }
+
+    fn succeed(limiter: &Limiter, times: usize) {
+        for _ in 0..times {
+            limiter.call(|| Ok(())).unwrap();
+        }
+    }
+
+    #[test]
+    fn halves_when_overloaded_down_to_one() {
+        let limiter = Limiter::new(8, 0);
+        fail(&limiter, throttled);
+        assert_eq!(limiter.limit(), 4);
+        fail(&limiter, warmup);
+        assert_eq!(limiter.limit(), 2);
+        fail(&limiter, timed_out);
+        assert_eq!(limiter.limit(), 1);
+        fail(&limiter, throttled);
+        assert_eq!(limiter.limit(), 1);
+    }
+
+    #[test]
+    fn grows_one_call_every_limit_requests_up_to_max() {
+        let limiter = Limiter::new(8, 0);
+        fail(&limiter, throttled);
+        succeed(&limiter, 4);
+        assert_eq!(limiter.limit(), 4);
+        succeed(&limiter, 1);
+        assert_eq!(limiter.limit(), 5);
+        succeed(&limiter, 100);
+        assert_eq!(limiter.limit(), 8);
+    }
+
+    #[test]
+    fn slow_calls_and_rejects_do_not_shrink() {
+        let limiter = Limiter::new(8, 0);
+        succeed(&limiter, 10);
+        limiter
+            .call(|| {
+                thread::sleep(Duration::from_millis(50));
+                Ok(())
+            })
+            .unwrap();
+        fail(&limiter, || rpc_error(-26, "min relay fee not met"));
+        assert_eq!(limiter.limit(), 8);
+    }
+
+    #[test]
+    fn retries_only_transient_batch_calls() {
+        let limiter = Limiter::new(8, 1);
+        let requests = RefCell::new(vec![]);
+        let results = limiter
+            .call_batch(3, |pending| {
+                requests.borrow_mut().push(pending.to_vec());
+                Ok(pending
+                    .iter()
+                    .map(|&i| match (i, requests.borrow().len()) {
+                        (1, 1) => Err(warmup()),
+                        (2, _) => Err(rpc_error(-26, "dust")),
+                        _ => Ok(i),
+                    })
+                    .collect())
+            })
+            .unwrap();
+        assert_eq!(requests.into_inner(), vec![vec![0, 1, 2], vec![1]]);
+        assert_eq!(results[0].as_ref().unwrap(), &0);
+        assert_eq!(results[1].as_ref().unwrap(), &1);
+        assert!(results[2].is_err());
+        assert_eq!(limiter.retried(), 1);
+    }
+}
@@ -1,25 +1,135 @@
 extern crate bitcoincore_rpc;
 extern crate confy;
 use crate::zmqseq::ZmqThread;
-use anyhow::{Context, Result};
-use bitcoincore_rpc::{bitcoin::Txid, Auth, Client, RpcApi};
-use config::Config;
-use indicatif::ParallelProgressIterator;
+use anyhow::{bail, Context, Result};
+use bitcoincore_rpc::bitcoin::consensus::encode::deserialize;
+use bitcoincore_rpc::bitcoin::Transaction;
+use bitcoincore_rpc::json::GetMempoolEntryResult;
+use bitcoincore_rpc::{bitcoin::Txid, jsonrpc, Auth, Client, RpcApi};
+use checkpoint::Checkpoint;
+use config::{Command, Config, NodeConfig};
+use destination::{fan_out, Destination};
+use dryrun::{print_dry_run_results, DryRun};
 use indicatif::ProgressBar;
 use indicatif::ProgressStyle;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use std::path::PathBuf;
+use mempooldat::{MempoolDat, MempoolDatEntry, MempoolDatWriter};
+use merge::MergedSource;
+use metrics::{Node, METRICS};
+use node::RpcNode;
+use outcome::{OutcomeCounts, SendOutcome};
+use package::get_packages;
+use rayon::iter::ParallelIterator;
+use rayon::slice::ParallelSlice;
+use report::{MempoolSizes, Report, RunStatus};
+use scheduler::{LiveQueue, TxGraph};
+use serde::{Deserialize, Serialize};
+use source::Source;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::str;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+//Not in mempool, for getmempoolentry
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+//Set when stdout carries the json report, human output goes to stderr then.
+static OUTPUT_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+//println! into stdout, or into stderr if it is taken by the report.
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        if crate::OUTPUT_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod batch;
+mod checkpoint;
 mod config;
+mod destination;
+mod diff;
+mod dryrun;
+mod limiter;
+mod mempooldat;
+mod merge;
+mod metrics;
+mod node;
+mod outcome;
+mod package;
+mod report;
+mod scheduler;
+mod source;
 mod zmqseq;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct TxDepth {
     ancestor_count: usize,
     tx_id: Txid,
+    //In-mempool parents
+    depends: Vec<Txid>,
+    vsize: u64,
+    //Base fee in sats, unknown for mempool.dat txs
+    fee: Option<u64>,
+}
+
+///Counters and errors shared by all the threads sending txs.
+#[derive(Debug, Default)]
+struct Stats {
+    failed_query_txs: AtomicUsize,
+    //Txs already in every destination, not asked to source
+    skipped_txs: AtomicUsize,
+    //Txs missing inputs sent after their ancestors
+    recovered_txs: AtomicUsize,
+    //What destinations did with each tx sent
+    outcomes: OutcomeCounts,
+    sent_packages: AtomicUsize,
+    failed_packages: AtomicUsize,
+    //Vbytes and base fees of the rpc snapshot txs sent
+    copied_vbytes: AtomicU64,
+    copied_fees: AtomicU64,
+    vec_txs_error: Mutex<Vec<String>>,
+    //Dry run results
+    accepted_txs: AtomicUsize,
+    reject_reasons: Mutex<HashMap<String, usize>>,
+    too_large_txs: AtomicUsize,
+}
+
+impl Stats {
+    fn add_outcome(&self, dest: &Destination, outcome: SendOutcome, count: usize) {
+        self.outcomes.add(outcome, count);
+        dest.outcomes.add(outcome, count);
+        METRICS.outcomes.add(outcome, count);
+    }
+}
+
+///Where raw transactions are taken from: a source node or a mempool.dat file.
+trait TxSource: Sync {
+    fn get_raw_tx_hex(&self, txid: &Txid) -> Result<String>;
+
+    ///Takes several txs at once, a result for each one.
+    fn get_raw_txs_hex(&self, txids: &[&Txid]) -> Vec<Result<String>> {
+        txids.iter().map(|txid| self.get_raw_tx_hex(txid)).collect()
+    }
+
+    ///In-mempool ancestors of a tx, parents before children. None if the source has no mempool.
+    fn get_mempool_ancestors(&self, _txid: &Txid) -> Result<Vec<Txid>> {
+        Ok(vec![])
+    }
+}
+
+impl TxSource for MempoolDat {
+    fn get_raw_tx_hex(&self, txid: &Txid) -> Result<String> {
+        match self.entries.get(txid) {
+            Some(entry) => Ok(hex::encode(&entry.raw_tx)),
+            None => bail!("Transaction not found in mempool.dat"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -28,91 +138,371 @@ enum ClientType {
     Destination,
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
+    let started = Instant::now();
     let cfg = Config::load().context("Error loading configuration")?;
+    if cfg.report.is_some() && cfg.report_file.is_none() {
+        OUTPUT_TO_STDERR.store(true, Ordering::Relaxed);
+    }
     if cfg.verbose {
-        println!("{}", cfg);
+        outln!("{}", cfg);
+    }
+
+    match run(&cfg, started) {
+        Ok(status) => Ok(status.into()),
+        Err(err) if report::is_connection_error(&err) => {
+            eprintln!("Error: {:?}", err);
+            if cfg.report.is_some() {
+                let mut report = Report::new(&cfg)?;
+                report.set_status(RunStatus::ConnectionFailure);
+                report.error = Some(format!("{:#}", err));
+                report.set_total_duration(started.elapsed());
+                report.write(&cfg)?;
+            }
+            Ok(RunStatus::ConnectionFailure.into())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn run(cfg: &Config, started: Instant) -> Result<RunStatus> {
+    if let Some(address) = cfg.metrics_listen {
+        metrics::serve(address)?;
+    }
+
+    if let Some(Command::Diff(args)) = &cfg.command {
+        let source = get_source(cfg)?;
+        let dests = get_destinations(cfg)?;
+        diff::diff_mempools(&source, &dests[0], args, cfg)?;
+        return Ok(RunStatus::Success);
+    }
+
+    if let Some(path) = &cfg.from_mempool_dat {
+        let dests = get_destinations(cfg)?;
+        return import_mempool_dat(path, &dests, cfg);
+    }
+
+    if let Some(path) = &cfg.to_mempool_dat {
+        let source = get_source(cfg)?;
+        export_mempool_dat(&source, path, cfg)?;
+        return Ok(RunStatus::Success);
     }
 
-    let (source_client, dest_client) = get_clients(&cfg)?;
+    let source = get_source(cfg)?;
+    let extra_sources = get_extra_sources(cfg)?;
+    let dests = get_destinations(cfg)?;
 
     //If zmq option, then spawn a thread to receive zmq transactions while working.
-    let zmq_thread = match cfg.zmq_address {
-        Some(ref address) => Some(ZmqThread::spawn(address)),
-        None => None,
+    //Nothing is sent in a dry run, so txs arriving meanwhile do not matter.
+    let mut zmq_thread = if cfg.dry_run || !cfg.zmq_enabled() {
+        None
+    } else {
+        let (address, rawtx_address) = match &cfg.zmq_address {
+            Some(address) => (address.clone(), cfg.zmq_rawtx_address.clone()),
+            None => {
+                let (address, rawtx_address) =
+                    zmqseq::discover(&source.client, &cfg.source_ip_addr)?;
+                outln!("ZMQ interface found in source node: {}", address);
+                if let Some(rawtx_address) = &rawtx_address {
+                    outln!(
+                        "ZMQ rawtx interface found in source node: {}",
+                        rawtx_address
+                    );
+                }
+                (address, rawtx_address)
+            }
+        };
+        Some(ZmqThread::spawn(
+            &address,
+            rawtx_address.as_ref(),
+            Duration::from_secs(cfg.zmq_timeout),
+        )?)
     };
 
-    print_mempool_sizes(&source_client, &dest_client, &cfg, "(Beginning)\t")?;
+    if let Some(zmq_thread) = zmq_thread.as_mut().filter(|_| cfg.sync) {
+        sync_mempools(zmq_thread, &source, &dests, cfg)?;
+        return Ok(RunStatus::Success);
+    }
+
+    let mut report = Report::new(cfg)?;
+    report.set_mempool_sizes_before(print_mempool_sizes(
+        &source.client,
+        &dests,
+        cfg,
+        "(Beginning)\t",
+    )?);
+
+    //Thread-safe things...
+    let stats = Stats::default();
+
+    //First retransmit txs obtained vía RPC
+    let rpc_started = Instant::now();
+    let (layers, streamed) = copy_rpc_txs(
+        &source,
+        &extra_sources,
+        &dests,
+        zmq_thread.as_mut(),
+        &stats,
+        cfg,
+    )?;
+    report.layers = layers;
+    report.durations.rpc_copy_ms = rpc_started.elapsed().as_millis();
+
+    // If zmq option, then retransmit the ZMQ transactions still not sent.
+    let zmq_started = Instant::now();
+    report.zmq_txs = streamed + retransmit_zmq_txs(zmq_thread, &source, &dests, &stats, cfg);
+    report.durations.zmq_ms = zmq_started.elapsed().as_millis();
+
+    //If verbose mode, then print failed txs during retranmission.
+    print_failed_txs(cfg, &stats, &dests);
+    report.add_stats(&stats, &dests);
+
+    if cfg.dry_run {
+        print_dry_run_results(&stats);
+        report.add_dry_run_results(&stats);
+    } else {
+        report.set_mempool_sizes_after(print_mempool_sizes(
+            &source.client,
+            &dests,
+            cfg,
+            "(End)\t\t",
+        )?);
+
+        outln!("\nNote: Mempool sizes could not be the same at the end because of different peers connections, conflicting transactions or transaction arrival timing issues between nodes (among other causes).");
+    }
+
+    if cfg.report.is_some() {
+        report.set_total_duration(started.elapsed());
+        report.write(cfg)?;
+    }
+    Ok(report.status)
+}
+
+///Runs forever: an RPC copy pass every `reconcile_interval` seconds and, in between, forwards
+///every tx announced by ZMQ as soon as it arrives.
+fn sync_mempools(
+    zmq_thread: &mut ZmqThread,
+    source: &Source,
+    dests: &[Destination],
+    cfg: &Config,
+) -> Result<()> {
+    let interval = Duration::from_secs(cfg.reconcile_interval);
+    loop {
+        let stats = Stats::default();
+
+        //A node restart or a network hiccup should not stop the mirror, try again next pass.
+        let streamed = match print_mempool_sizes(&source.client, dests, cfg, "(Reconciling)\t")
+            .and_then(|_| copy_rpc_txs(source, &[], dests, Some(&mut *zmq_thread), &stats, cfg))
+        {
+            Ok((_, streamed)) => streamed,
+            Err(err) => {
+                eprintln!("Reconciliation pass failed: {:?}", err);
+                0
+            }
+        };
+
+        let txs = zmq_thread.for_each_until(
+            Instant::now() + interval,
+            &source.client,
+            |txid, raw_tx| {
+                retransmit_zmq_tx(txid, raw_tx, source, dests, &stats, cfg);
+            },
+            || resync_mempools(source, dests, &stats, cfg),
+        )?;
+        outln!(
+            "\nForwarded {} transactions from ZMQ interface since last reconciliation",
+            streamed + txs
+        );
+
+        print_failed_txs(cfg, &stats, dests);
+    }
+}
+
+///Copies source mempool, merged with the extra sources ones, via rpc. Returns the number of txs
+///in each layer and the number of ZMQ txs sent meanwhile. Only the ZMQ txs after the copy
+///snapshot are sent.
+///
+///With --state-file the txs to copy and the ones sent are written as the copy runs, --resume
+///takes them from there instead of from source.
+fn copy_rpc_txs(
+    source: &Source,
+    extra_sources: &[Source],
+    dests: &[Destination],
+    mut zmq_thread: Option<&mut ZmqThread>,
+    stats: &Stats,
+    cfg: &Config,
+) -> Result<(Vec<usize>, usize)> {
+    let (mut merged, checkpoint) = match &cfg.state_file {
+        Some(path) if cfg.resume => {
+            let (checkpoint, txs, sent) = Checkpoint::resume(path, cfg)
+                .context(format!("Can't resume copy from state file: {:?}", path))?;
+            outln!(
+                "\nResuming copy: {} transactions already sent, {} pending",
+                sent,
+                txs.len()
+            );
+            //Txs arriving to source between the interruption and now are neither in the state
+            //file nor in ZMQ, they are not copied. Only the ones arriving from now on are.
+            if let Some(zmq_thread) = zmq_thread.as_mut() {
+                let sequence = get_raw_mempool_sequence(&source.client)?.mempool_sequence;
+                zmq_thread.set_snapshot_sequence(sequence);
+            }
+            (
+                MergedSource::new(source, txs, extra_sources),
+                Some(checkpoint),
+            )
+        }
+        state_file => {
+            let (vec, sequence) = get_tx_dept_vec(source, cfg)?;
+            if let Some(zmq_thread) = zmq_thread.as_mut() {
+                zmq_thread.set_snapshot_sequence(sequence);
+            }
+            let merged = merge::merge(source, vec, extra_sources, cfg)?;
+            if !extra_sources.is_empty() {
+                outln!(
+                    "\nMerged mempools of {} sources: {} transactions, {} conflicting transactions left out",
+                    extra_sources.len() + 1,
+                    merged.txs.len(),
+                    merged.dropped
+                );
+            }
+            let checkpoint = match state_file {
+                Some(path) => Some(
+                    Checkpoint::create(path, &merged.txs, cfg)
+                        .context(format!("Can't write state file: {:?}", path))?,
+                ),
+                None => None,
+            };
+            (merged, checkpoint)
+        }
+    };
 
-    let vec = get_tx_dept_vec(&source_client, cfg.fast_mode)?;
+    //Txs already in destination would be rejected, they are not asked to source either. Txs
+    //pending in a resumed copy could have been sent before the interruption.
+    let dest_txids = get_common_dest_txids(dests)?;
+    let count = merged.txs.len();
+    merged.txs.retain(|tx| !dest_txids.contains(&tx.tx_id));
+    let skipped = count - merged.txs.len();
+    stats.skipped_txs.fetch_add(skipped, Ordering::SeqCst);
+    if skipped > 0 {
+        outln!(
+            "\nSkipping {} transactions already in destination mempool",
+            skipped
+        );
+    }
+    let vec = &merged.txs;
 
     //vec2 is a vector of vectors containing txs with same ancestor_count:
     //(vec2[ancestor_count-1] has a vector with all tx having ancestor_count-1)
     let vec2 = get_mempool_layers(vec);
 
-    list_mempool_layers(&cfg, &vec2);
-
-    //Thread-safe things...
-    let failed_query_txs = AtomicUsize::new(0);
-    let failed_sent_txs = AtomicUsize::new(0);
-    let vec_txs_error: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+    list_mempool_layers(cfg, &vec2);
 
-    //First retransmit txs obtained vía RPC
+    let mut streamed = 0;
     retransmit_rpc_txs(
-        vec2,
-        &source_client,
-        &dest_client,
-        &failed_sent_txs,
-        &failed_query_txs,
-        &cfg,
-        &vec_txs_error,
+        vec,
+        &merged,
+        dests,
+        checkpoint.as_ref(),
+        stats,
+        cfg,
+        |queue| {
+            if let Some(zmq_thread) = zmq_thread {
+                streamed = stream_zmq_txs(zmq_thread, queue, source, stats, cfg);
+            }
+        },
     );
+    Ok((vec2.iter().map(|layer| layer.len()).collect(), streamed))
+}
 
-    // If zmq option, then retransmit all ZMQ transactions received during execution.
-    retransmit_zmq_txs(
-        zmq_thread,
-        &source_client,
-        &dest_client,
-        &failed_sent_txs,
-        &failed_query_txs,
-        &cfg,
-        &vec_txs_error,
+//Hands ZMQ txs to the rpc copy as they arrive, they are sent after their parents and before
+//the rest of the copy. Returns the number of txs.
+fn stream_zmq_txs(
+    zmq_thread: &mut ZmqThread,
+    queue: &LiveQueue<String>,
+    source: &Source,
+    stats: &Stats,
+    cfg: &Config,
+) -> usize {
+    let res = zmq_thread.stream_until(
+        &source.client,
+        || queue.graph_done(),
+        |txid, raw_tx| {
+            let tx_hex = match raw_tx {
+                Some(raw_tx) => hex::encode(raw_tx),
+                None => match get_raw_tx_hex(txid, source, stats, cfg) {
+                    Some(tx_hex) => tx_hex,
+                    None => return,
+                },
+            };
+            queue.push(*txid, &spent_txids(&tx_hex), tx_hex);
+        },
     );
+    res.unwrap_or_else(|err| {
+        eprintln!("ZMQ interface error: {:?}", err);
+        0
+    })
+}
 
-    //If verbose mode, then print failed txs during retranmission.
-    print_failed_txs(&cfg, vec_txs_error, failed_query_txs, failed_sent_txs);
-
-    print_mempool_sizes(&source_client, &dest_client, &cfg, "(End)\t\t")?;
-
-    println!("\nNote: Mempool sizes could not be the same at the end because of different peers connections, conflicting transactions or transaction arrival timing issues between nodes (among other causes).");
-
-    Ok(())
+//Txs whose outputs are spent by the tx, none if it can't be decoded.
+fn spent_txids(tx_hex: &str) -> Vec<Txid> {
+    hex::decode(tx_hex)
+        .ok()
+        .and_then(|raw_tx| deserialize::<Transaction>(&raw_tx).ok())
+        .map(|tx| {
+            tx.input
+                .iter()
+                .map(|input| input.previous_output.txid)
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
-fn get_clients(cfg: &Config) -> Result<(Client, Client), anyhow::Error> {
-    let source_client = if let Some(path) = &cfg.source_cookie_auth_path {
-        get_client_cookie(&cfg.source_ip_addr, path.clone(), ClientType::Source)?
+fn get_source(cfg: &Config) -> Result<Source> {
+    let client = if let Some(path) = &cfg.source_cookie_auth_path {
+        get_client_cookie(&cfg.source_ip_addr, path.clone(), ClientType::Source)
     } else {
         get_client_user_passw(
             &cfg.source_ip_addr,
             cfg.source_user.as_ref().unwrap().clone(),
             cfg.source_passwd.as_ref().unwrap().clone(),
             ClientType::Source,
-        )?
-    };
+        )
+    }?;
+    Ok(Source::new(&cfg.source_ip_addr, client, cfg.max_retries))
+}
+
+fn get_extra_sources(cfg: &Config) -> Result<Vec<Source>> {
+    cfg.extra_source
+        .iter()
+        .map(|source| {
+            let client = get_node_client(source, ClientType::Source)?;
+            Ok(Source::new(&source.url, client, cfg.max_retries))
+        })
+        .collect()
+}
 
-    let dest_client = if let Some(path) = &cfg.dest_cookie_auth_path {
-        get_client_cookie(&cfg.dest_ip_addr, path.clone(), ClientType::Destination)?
+fn get_destinations(cfg: &Config) -> Result<Vec<Destination>> {
+    cfg.destinations()
+        .iter()
+        .map(|dest| {
+            let client = get_node_client(dest, ClientType::Destination)?;
+            Ok(Destination::new(dest, client, cfg.max_retries))
+        })
+        .collect()
+}
+
+fn get_node_client(node: &NodeConfig, client_type: ClientType) -> Result<Client> {
+    if let Some(path) = &node.cookie_auth_path {
+        get_client_cookie(&node.url, path.clone(), client_type)
     } else {
         get_client_user_passw(
-            &cfg.dest_ip_addr,
-            cfg.dest_user.as_ref().unwrap().clone(),
-            cfg.dest_passwd.as_ref().unwrap().clone(),
-            ClientType::Destination,
-        )?
-    };
-
-    Ok((source_client, dest_client))
+            &node.url,
+            node.user.clone().unwrap_or_default(),
+            node.passwd.clone().unwrap_or_default(),
+            client_type,
+        )
+    }
 }
 
 fn get_client_cookie(ip: &str, path: PathBuf, client_type: ClientType) -> Result<Client> {
@@ -133,45 +523,167 @@ fn get_client_user_passw(
     ))
 }
 
-fn list_mempool_layers(cfg: &Config, vec2: &Vec<Vec<Txid>>) {
+fn list_mempool_layers(cfg: &Config, vec2: &[Vec<Txid>]) {
     if cfg.verbose {
-        println!("\nTransactions dependencies:\n");
+        outln!("\nTransactions dependencies:\n");
         for (i, txid_vec) in vec2.iter().enumerate() {
-            println!("#Txs depending of {} parents: {}", i, txid_vec.len());
+            outln!("#Txs depending of {} parents: {}", i, txid_vec.len());
         }
-        println!("");
+        outln!();
+    }
+}
+
+//getrawmempool result with mempool_sequence
+#[derive(Debug, Deserialize)]
+struct RawMempoolSequence {
+    txids: Vec<Txid>,
+    mempool_sequence: u64,
+}
+
+fn get_raw_mempool_sequence(client: &Client) -> Result<RawMempoolSequence> {
+    match client.call("getrawmempool", &[false.into(), true.into()]) {
+        Ok(res) => Ok(res),
+        //bitcoind < 0.21 has not mempool_sequence, every ZMQ event is replayed then.
+        Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(_))) => Ok(RawMempoolSequence {
+            txids: client.get_raw_mempool()?,
+            mempool_sequence: 0,
+        }),
+        Err(err) => Err(err.into()),
     }
 }
 
-fn get_tx_dept_vec(source_client: &Client, fast_mode: bool) -> Result<Vec<TxDepth>> {
-    if fast_mode {
-        let vec: Vec<TxDepth> = source_client
-            .get_raw_mempool_verbose()?
+///Returns the source mempool txs and the mempool sequence they are taken at.
+fn get_tx_dept_vec(node: &RpcNode, cfg: &Config) -> Result<(Vec<TxDepth>, u64)> {
+    //Verbose getrawmempool can't return the sequence, it is taken before so ZMQ events of txs
+    //added meanwhile are replayed. Some txs could be sent twice.
+    let snapshot = get_raw_mempool_sequence(&node.client)?;
+    if cfg.fast_mode {
+        let vec: Vec<TxDepth> = node
+            .call(|client| client.get_raw_mempool_verbose())?
             .iter()
-            .map(|(tx_ide, mempool_entry)| TxDepth {
-                ancestor_count: mempool_entry.ancestor_count as usize,
-                tx_id: tx_ide.clone(),
-            })
+            .map(|(tx_id, entry)| tx_depth(*tx_id, entry))
             .collect();
-        return Ok(vec);
+        Ok((vec, snapshot.mempool_sequence))
     } else {
-        let vec: Vec<TxDepth> = source_client
-            .get_raw_mempool()?
-            .par_iter()
-            .filter_map(|tx_id| match source_client.get_mempool_entry(tx_id) {
-                Ok(entry) => Some((tx_id, entry)),
-                Err(_) => None, //If tx_id do not exist we don't care
-            })
-            .map(|(tx_id, entry)| TxDepth {
-                ancestor_count: entry.ancestor_count as usize,
-                tx_id: tx_id.clone(),
-            })
+        Ok((
+            get_tx_depths(node, &snapshot.txids, cfg),
+            snapshot.mempool_sequence,
+        ))
+    }
+}
+
+//Txs removed from the mempool meanwhile are left out. Txs whose entry can't be read for other
+//reasons are left out too, and counted in a warning.
+fn get_mempool_entries<'a>(
+    node: &RpcNode,
+    txids: &'a [Txid],
+    cfg: &Config,
+) -> Vec<(&'a Txid, GetMempoolEntryResult)> {
+    let failed = AtomicUsize::new(0);
+    let entries = txids
+        .par_chunks(cfg.batch_size as usize)
+        .flat_map_iter(|chunk| {
+            let params: Vec<Vec<serde_json::Value>> = chunk
+                .iter()
+                .map(|tx_id| vec![tx_id.to_string().into()])
+                .collect();
+            //If the batch fails as a whole, each tx is asked on its own
+            let results = node
+                .call_batch::<GetMempoolEntryResult>("getmempoolentry", &params)
+                .unwrap_or_else(|_| {
+                    chunk
+                        .iter()
+                        .map(|tx_id| node.call(|client| client.get_mempool_entry(tx_id)))
+                        .collect()
+                });
+            let failed = &failed;
+            chunk
+                .iter()
+                .zip(results)
+                .filter_map(move |(tx_id, res)| match res {
+                    Ok(entry) => Some((tx_id, entry)),
+                    //If tx_id do not exist we don't care
+                    Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err)))
+                        if rpc_err.code == RPC_INVALID_ADDRESS_OR_KEY =>
+                    {
+                        None
+                    }
+                    Err(_) => {
+                        failed.fetch_add(1, Ordering::SeqCst);
+                        None
+                    }
+                })
+        })
+        .collect();
+    let failed = failed.into_inner();
+    if failed > 0 {
+        eprintln!(
+            "Can't get the mempool entry of {} txs, they are not copied",
+            failed
+        );
+    }
+    entries
+}
+
+fn get_tx_depths(node: &RpcNode, txids: &[Txid], cfg: &Config) -> Vec<TxDepth> {
+    get_mempool_entries(node, txids, cfg)
+        .iter()
+        .map(|(tx_id, entry)| tx_depth(**tx_id, entry))
+        .collect()
+}
+
+fn tx_depth(tx_id: Txid, entry: &GetMempoolEntryResult) -> TxDepth {
+    TxDepth {
+        ancestor_count: entry.ancestor_count as usize,
+        tx_id,
+        depends: entry.depends.clone(),
+        vsize: entry.vsize,
+        fee: Some(entry.fees.base.to_sat()),
+    }
+}
+
+///Sends the source txs missing in some destination, used when ZMQ messages have been lost.
+///Returns the source mempool sequence it is based on.
+fn resync_mempools(
+    source: &Source,
+    dests: &[Destination],
+    stats: &Stats,
+    cfg: &Config,
+) -> Result<u64> {
+    let snapshot = get_raw_mempool_sequence(&source.client)?;
+    let dest_txids = get_common_dest_txids(dests)?;
+    let missing: Vec<Txid> = snapshot
+        .txids
+        .into_iter()
+        .filter(|txid| !dest_txids.contains(txid))
+        .collect();
+    outln!(
+        "\nZMQ messages lost, sending {} transactions missing in destination",
+        missing.len()
+    );
+    let vec = get_tx_depths(source, &missing, cfg);
+    retransmit_rpc_txs(&vec, source, dests, None, stats, cfg, |_| {});
+    Ok(snapshot.mempool_sequence)
+}
+
+//Txs in the mempool of every destination.
+fn get_common_dest_txids(dests: &[Destination]) -> Result<HashSet<Txid>> {
+    let mut common: Option<HashSet<Txid>> = None;
+    for dest in dests {
+        let txids: HashSet<Txid> = dest
+            .call(|client| client.get_raw_mempool())
+            .context(format!("Can't connect to {}", dest.url))?
+            .into_iter()
             .collect();
-        return Ok(vec);
+        common = Some(match common {
+            Some(common) => common.intersection(&txids).copied().collect(),
+            None => txids,
+        });
     }
+    Ok(common.unwrap_or_default())
 }
 
-fn get_mempool_layers(vec: Vec<TxDepth>) -> Vec<Vec<Txid>> {
+fn get_mempool_layers(vec: &[TxDepth]) -> Vec<Vec<Txid>> {
     let mut vec2: Vec<Vec<Txid>> = vec![];
     for tx_depth in vec {
         let ancestor_index = tx_depth.ancestor_count - 1;
@@ -185,147 +697,770 @@ fn get_mempool_layers(vec: Vec<TxDepth>) -> Vec<Vec<Txid>> {
 
 fn retransmit_zmq_txs(
     zmq_thread: Option<ZmqThread>,
-    source_client: &Client,
-    dest_client: &Client,
-    failed_sent_txs: &AtomicUsize,
-    failed_query_txs: &AtomicUsize,
+    source: &Source,
+    dests: &[Destination],
+    stats: &Stats,
     cfg: &Config,
-    vec_txs_error: &Arc<Mutex<Vec<String>>>,
-) {
-    if zmq_thread.is_some() {
-        println!("");
-        let sp = create_spinner();
-        let txs = zmq_thread.unwrap().for_each(|txid| {
-            retransmit(
-                txid,
-                source_client,
-                dest_client,
-                failed_sent_txs,
-                failed_query_txs,
-                cfg,
-                vec_txs_error,
-            )
-        });
-        sp.finish_with_message(format!(
-            "Done. Sent {} additional transactions from ZMQ iterface",
-            txs
-        ));
-        println!("\n");
-    }
+) -> usize {
+    let Some(zmq_thread) = zmq_thread else {
+        return 0;
+    };
+    outln!();
+    let sp = create_spinner();
+    let txs = zmq_thread.for_each(
+        &source.client,
+        |txid, raw_tx| {
+            retransmit_zmq_tx(txid, raw_tx, source, dests, stats, cfg);
+        },
+        || resync_mempools(source, dests, stats, cfg),
+    );
+    sp.finish_with_message(format!(
+        "Done. Sent {} additional transactions from ZMQ iterface",
+        txs
+    ));
+    outln!("\n");
+    txs
 }
 
+///Sends every tx as soon as all its parents have been sent. Low fee parents are sent in a
+///package together with a child paying for them. Txs pushed by `feed` meanwhile are sent first.
+///
+///Each tx is taken from source once and sent to every destination.
 fn retransmit_rpc_txs(
-    vec2: Vec<Vec<Txid>>,
-    source_client: &Client,
-    dest_client: &Client,
-    failed_sent_txs: &AtomicUsize,
-    failed_query_txs: &AtomicUsize,
+    vec: &[TxDepth],
+    source_client: &impl TxSource,
+    dests: &[Destination],
+    checkpoint: Option<&Checkpoint>,
+    stats: &Stats,
     cfg: &Config,
-    vec_txs_error: &Arc<Mutex<Vec<String>>>,
+    feed: impl FnOnce(&LiveQueue<String>) + Send,
 ) {
-    for (i, txid_vec) in vec2.iter().enumerate() {
-        let style = ProgressStyle::with_template(
-            "{prefix} [{elapsed_precise}] {wide_bar} {pos:>7}/{len:7} ",
-        )
-        .unwrap();
-        txid_vec
-            .par_iter()
-            .progress_with_style(style)
-            .with_prefix(format!(
-                "Txs depending of {} parents: {}",
-                i,
-                txid_vec.len()
-            ))
-            .for_each(|tx_id| {
-                retransmit(
-                    tx_id,
-                    source_client,
-                    dest_client,
-                    failed_sent_txs,
-                    failed_query_txs,
-                    cfg,
-                    vec_txs_error,
-                )
-            });
+    //Packages are made for the first destination min fee
+    let packages = match dests[0].client.get_mempool_info() {
+        Ok(info) => get_packages(vec, info.mempool_min_fee),
+        Err(_) => vec![],
+    };
+    let packages_count = packages.len();
+    let graph = TxGraph::new(
+        vec.iter()
+            .map(|tx_depth| (tx_depth.tx_id, &tx_depth.depends[..])),
+        packages,
+    );
+    let style =
+        ProgressStyle::with_template("{prefix} [{elapsed_precise}] {wide_bar} {pos:>7}/{len:7} ")
+            .unwrap();
+    let pb = ProgressBar::new(graph.len() as u64)
+        .with_style(style)
+        .with_prefix(format!("Txs sent: {}", graph.len()));
+    //Dry run is only for one destination
+    let dry_run = if cfg.dry_run {
+        match DryRun::new(vec, &dests[0].client) {
+            Ok(dry_run) => Some(dry_run),
+            Err(err) => {
+                eprintln!("Can't get destination mempool for dry run: {:?}", err);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let by_txid: HashMap<Txid, &TxDepth> = vec.iter().map(|tx| (tx.tx_id, tx)).collect();
+    //Txs are copied once accepted by some destination, and done once accepted by all of them
+    let add_accepted = |accepted: Vec<Vec<Txid>>| {
+        let mut counts: HashMap<Txid, usize> = HashMap::new();
+        for txid in accepted.into_iter().flatten() {
+            *counts.entry(txid).or_default() += 1;
+        }
+        let mut done = vec![];
+        for (txid, count) in counts {
+            if let Some(tx) = by_txid.get(&txid) {
+                stats.copied_vbytes.fetch_add(tx.vsize, Ordering::SeqCst);
+                stats
+                    .copied_fees
+                    .fetch_add(tx.fee.unwrap_or(0), Ordering::SeqCst);
+            }
+            if count == dests.len() {
+                done.push(txid);
+            }
+        }
+        if let Some(checkpoint) = checkpoint {
+            if let Err(err) = checkpoint.record(&done) {
+                eprintln!("Can't write state file: {:?}", err);
+            }
+        }
+    };
+    //Single txs ready at the same time are taken and sent together
+    let send_units = |units: &[&[Txid]]| {
+        let mut singles = vec![];
+        for unit in units {
+            if let Some(dry_run) = &dry_run {
+                dry_run.test_unit(unit, source_client, &dests[0].client, stats, cfg);
+            } else if unit.len() == 1 {
+                singles.push(&unit[0]);
+            } else {
+                add_accepted(retransmit_package(unit, source_client, dests, stats, cfg));
+            }
+        }
+        add_accepted(retransmit(&singles, source_client, dests, stats, cfg));
+        pb.inc(units.iter().map(|unit| unit.len() as u64).sum());
+    };
+    //Destinations limit their own concurrency
+    let threads = dests
+        .iter()
+        .map(|dest| dest.limiter.max())
+        .max()
+        .unwrap_or(1);
+    scheduler::for_each_in_order(
+        &graph,
+        threads,
+        cfg.batch_size as usize,
+        send_units,
+        |txid, tx_hex| {
+            send_to_all(txid, tx_hex, source_client, dests, stats, cfg);
+        },
+        feed,
+    );
+    pb.finish();
+    if packages_count > 0 && !cfg.dry_run {
+        let unsupported: Vec<&Destination> = dests
+            .iter()
+            .filter(|dest| dest.no_submitpackage.load(Ordering::SeqCst))
+            .collect();
+        for dest in &unsupported {
+            outln!(
+                "Destination {} does not support submitpackage, {} packages sent tx by tx",
+                dest.url,
+                packages_count
+            );
+        }
+        if unsupported.len() < dests.len() {
+            outln!(
+                "Packages sent: {} ({} accepted, {} rejected)",
+                packages_count,
+                stats.sent_packages.load(Ordering::SeqCst),
+                stats.failed_packages.load(Ordering::SeqCst)
+            );
+        }
     }
 }
+
+//Returns the txs accepted by each destination.
 fn retransmit(
+    txids: &[&Txid],
+    source_client: &impl TxSource,
+    dests: &[Destination],
+    stats: &Stats,
+    cfg: &Config,
+) -> Vec<Vec<Txid>> {
+    let txs = get_raw_txs_hex(txids, source_client, stats, cfg);
+    if txs.is_empty() {
+        return vec![];
+    }
+    fan_out(dests, |dest| {
+        send_txs(&txs, source_client, dest, stats, cfg)
+    })
+}
+
+//Txs received from ZMQ rawtx are sent as they are, the rest are asked to source.
+fn retransmit_zmq_tx(
     txid: &Txid,
-    source_client: &Client,
-    dest_client: &Client,
-    failed_sent_txs: &AtomicUsize,
-    failed_query_txs: &AtomicUsize,
+    raw_tx: Option<Vec<u8>>,
+    source: &Source,
+    dests: &[Destination],
+    stats: &Stats,
     cfg: &Config,
-    vec_txs_error: &Arc<Mutex<Vec<String>>>,
-) {
-    match source_client.get_raw_transaction_hex(txid, None) {
-        Ok(tx_hex) => match dest_client.send_raw_transaction(tx_hex) {
-            Ok(_) => (),
+) -> bool {
+    match raw_tx {
+        Some(raw_tx) => send_to_all(txid, hex::encode(raw_tx), source, dests, stats, cfg),
+        None => retransmit(&[txid], source, dests, stats, cfg)
+            .iter()
+            .any(|accepted| !accepted.is_empty()),
+    }
+}
+
+///Sends a child-with-parents package to every destination. Returns the txs accepted by each
+///destination.
+fn retransmit_package(
+    package: &[Txid],
+    source_client: &impl TxSource,
+    dests: &[Destination],
+    stats: &Stats,
+    cfg: &Config,
+) -> Vec<Vec<Txid>> {
+    let txids: Vec<&Txid> = package.iter().collect();
+    let txs = get_raw_txs_hex(&txids, source_client, stats, cfg);
+    fan_out(dests, |dest| {
+        send_package(package, &txs, source_client, dest, stats, cfg)
+    })
+}
+
+///Sends a package using submitpackage, or tx by tx if destination node does not support it.
+///Returns the txs accepted by destination.
+fn send_package(
+    package: &[Txid],
+    txs: &[(&Txid, String)],
+    source_client: &impl TxSource,
+    dest: &Destination,
+    stats: &Stats,
+    cfg: &Config,
+) -> Vec<Txid> {
+    //A missing tx makes the package invalid, send what we have.
+    if txs.len() < package.len() || dest.no_submitpackage.load(Ordering::SeqCst) {
+        return send_txs(txs, source_client, dest, stats, cfg);
+    }
+
+    let txs_hex: Vec<String> = txs.iter().map(|(_, tx_hex)| tx_hex.clone()).collect();
+    match dest.call(|client| package::submit_package(client, txs_hex.clone())) {
+        Ok(res) if res.is_success() => {
+            stats.sent_packages.fetch_add(1, Ordering::SeqCst);
+            stats.add_outcome(dest, SendOutcome::Sent, package.len());
+            package.to_vec()
+        }
+        Ok(res) => {
+            stats.failed_packages.fetch_add(1, Ordering::SeqCst);
+            if cfg.verbose {
+                stats.vec_txs_error.lock().unwrap().push(format!(
+                    "Failed destination {} package: {:?} Reason: {:?}",
+                    dest.url, package, res.package_msg
+                ));
+            }
+            let mut sent = vec![];
+            for txid in package {
+                let tx_res = res.tx_results.values().find(|tx| tx.txid == *txid);
+                //Txs without a result were not evaluated because of the package error.
+                let reason = match tx_res {
+                    Some(tx) => tx.error.as_deref(),
+                    None => Some(res.package_msg.as_deref().unwrap_or("unknown")),
+                };
+                match reason {
+                    Some(reason) => record_failure(
+                        txid,
+                        SendOutcome::from_reject_reason(reason),
+                        reason,
+                        dest,
+                        stats,
+                        cfg,
+                    ),
+                    None => {
+                        stats.add_outcome(dest, SendOutcome::Sent, 1);
+                        sent.push(*txid);
+                    }
+                }
+            }
+            sent
+        }
+        Err(err) if package::is_unsupported(&err) => {
+            dest.no_submitpackage.store(true, Ordering::SeqCst);
+            send_txs(txs, source_client, dest, stats, cfg)
+        }
+        Err(err) => {
+            stats.failed_packages.fetch_add(1, Ordering::SeqCst);
+            let outcome = SendOutcome::from_error(&err);
+            stats.add_outcome(dest, outcome, package.len());
+            if cfg.verbose {
+                stats.vec_txs_error.lock().unwrap().push(format!(
+                    "Failed destination {} package: {:?} Outcome: {} Reason: {:?}",
+                    dest.url, package, outcome, err
+                ));
+            }
+            vec![]
+        }
+    }
+}
+
+fn get_raw_tx_hex(
+    txid: &Txid,
+    source_client: &impl TxSource,
+    stats: &Stats,
+    cfg: &Config,
+) -> Option<String> {
+    get_raw_txs_hex(&[txid], source_client, stats, cfg)
+        .pop()
+        .map(|(_, tx_hex)| tx_hex)
+}
+
+//Takes the txs from source at once, the ones that can't be taken are counted as failed.
+fn get_raw_txs_hex<'a>(
+    txids: &[&'a Txid],
+    source_client: &impl TxSource,
+    stats: &Stats,
+    cfg: &Config,
+) -> Vec<(&'a Txid, String)> {
+    if txids.is_empty() {
+        return vec![];
+    }
+    txids
+        .iter()
+        .zip(source_client.get_raw_txs_hex(txids))
+        .filter_map(|(txid, res)| match res {
+            Ok(tx_hex) => Some((*txid, tx_hex)),
             Err(err) => {
-                failed_sent_txs.fetch_add(1, Ordering::SeqCst);
+                stats.failed_query_txs.fetch_add(1, Ordering::SeqCst);
+                METRICS.failed_query_txs.fetch_add(1, Ordering::SeqCst);
                 if cfg.verbose {
-                    vec_txs_error.lock().unwrap().push(format!(
-                        "Failed destination TxId: {:?} Reason: {:?}",
-                        txid, err
-                    ));
+                    stats
+                        .vec_txs_error
+                        .lock()
+                        .unwrap()
+                        .push(format!("Failed source TxId: {:?} Reason: {:?}", txid, err));
                 }
+                None
             }
-        },
+        })
+        .collect()
+}
+
+//Returns true if some destination accepted the tx.
+fn send_to_all(
+    txid: &Txid,
+    tx_hex: String,
+    source_client: &impl TxSource,
+    dests: &[Destination],
+    stats: &Stats,
+    cfg: &Config,
+) -> bool {
+    fan_out(dests, |dest| {
+        send_tx(txid, &tx_hex, source_client, dest, stats, cfg)
+    })
+    .contains(&true)
+}
+
+//Returns true if destination accepted the tx.
+fn send_tx(
+    txid: &Txid,
+    tx_hex: &str,
+    source_client: &impl TxSource,
+    dest: &Destination,
+    stats: &Stats,
+    cfg: &Config,
+) -> bool {
+    let res = dest.call(|client| client.send_raw_transaction(tx_hex));
+    record_send(txid, tx_hex, res, source_client, dest, stats, cfg)
+}
+
+//Sends txs in order, --batch-size at a time. Returns the ones accepted.
+fn send_txs(
+    txs: &[(&Txid, String)],
+    source_client: &impl TxSource,
+    dest: &Destination,
+    stats: &Stats,
+    cfg: &Config,
+) -> Vec<Txid> {
+    txs.chunks(cfg.batch_size as usize)
+        .flat_map(|chunk| send_tx_batch(chunk, source_client, dest, stats, cfg))
+        .collect()
+}
+
+fn send_tx_batch(
+    txs: &[(&Txid, String)],
+    source_client: &impl TxSource,
+    dest: &Destination,
+    stats: &Stats,
+    cfg: &Config,
+) -> Vec<Txid> {
+    let params: Vec<Vec<serde_json::Value>> = txs
+        .iter()
+        .map(|(_, tx_hex)| vec![tx_hex.clone().into()])
+        .collect();
+    match dest.call_batch::<Txid>("sendrawtransaction", &params) {
+        Ok(results) => txs
+            .iter()
+            .zip(results)
+            .filter_map(|((txid, tx_hex), res)| {
+                record_send(txid, tx_hex, res, source_client, dest, stats, cfg).then_some(**txid)
+            })
+            .collect(),
         Err(err) => {
-            failed_query_txs.fetch_add(1, Ordering::SeqCst);
-            if cfg.verbose {
-                vec_txs_error
-                    .lock()
-                    .unwrap()
-                    .push(format!("Failed source TxId: {:?} Reason: {:?}", txid, err));
+            let reason = format!("{:?}", err);
+            for (txid, _) in txs {
+                record_failure(
+                    txid,
+                    SendOutcome::from_error(&err),
+                    &reason,
+                    dest,
+                    stats,
+                    cfg,
+                );
+            }
+            vec![]
+        }
+    }
+}
+
+//Counts the result of sending a tx, returns true if it was accepted. Txs missing inputs are sent
+//again after their ancestors.
+fn record_send(
+    txid: &Txid,
+    tx_hex: &str,
+    res: Result<Txid, bitcoincore_rpc::Error>,
+    source_client: &impl TxSource,
+    dest: &Destination,
+    stats: &Stats,
+    cfg: &Config,
+) -> bool {
+    let res = match res {
+        Err(err) if SendOutcome::from_error(&err) == SendOutcome::MissingInputs => {
+            match send_with_ancestors(txid, tx_hex, source_client, dest, stats, cfg) {
+                Some(Ok(txid)) => {
+                    stats.recovered_txs.fetch_add(1, Ordering::SeqCst);
+                    Ok(txid)
+                }
+                Some(Err(err)) => Err(err),
+                None => Err(err),
             }
         }
+        res => res,
     };
+    match res {
+        Ok(_) => {
+            stats.add_outcome(dest, SendOutcome::Sent, 1);
+            true
+        }
+        Err(err) => {
+            record_failure(
+                txid,
+                SendOutcome::from_error(&err),
+                &format!("{:?}", err),
+                dest,
+                stats,
+                cfg,
+            );
+            false
+        }
+    }
 }
 
-fn print_failed_txs(
+//A tx gaining a parent in source after the copy snapshot misses inputs in destination. The source
+//mempool ancestors missing in destination are sent first, then the tx again. None if there are no
+//ancestors to send.
+fn send_with_ancestors(
+    txid: &Txid,
+    tx_hex: &str,
+    source_client: &impl TxSource,
+    dest: &Destination,
+    stats: &Stats,
+    cfg: &Config,
+) -> Option<Result<Txid, bitcoincore_rpc::Error>> {
+    let ancestors = source_client.get_mempool_ancestors(txid).ok()?;
+    let params: Vec<Vec<serde_json::Value>> = ancestors
+        .iter()
+        .map(|txid| vec![txid.to_string().into()])
+        .collect();
+    let in_dest = dest
+        .call_batch::<serde_json::Value>("getmempoolentry", &params)
+        .ok()?;
+    let missing: Vec<&Txid> = ancestors
+        .iter()
+        .zip(in_dest)
+        .filter(|(_, res)| res.is_err())
+        .map(|(txid, _)| txid)
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+    //Ancestors rejected are not counted, the tx missing inputs is.
+    for (_, ancestor_hex) in get_raw_txs_hex(&missing, source_client, stats, cfg) {
+        if dest
+            .call(|client| client.send_raw_transaction(ancestor_hex.as_str()))
+            .is_ok()
+        {
+            stats.add_outcome(dest, SendOutcome::Sent, 1);
+        }
+    }
+    Some(dest.call(|client| client.send_raw_transaction(tx_hex)))
+}
+
+//Already-in-mempool/chain outcomes are counted but not reported as errors.
+fn record_failure(
+    txid: &Txid,
+    outcome: SendOutcome,
+    reason: &str,
+    dest: &Destination,
+    stats: &Stats,
     cfg: &Config,
-    vec_txs_error: Arc<Mutex<Vec<String>>>,
-    failed_query_txs: AtomicUsize,
-    failed_sent_txs: AtomicUsize,
 ) {
+    stats.add_outcome(dest, outcome, 1);
+    if cfg.verbose && outcome.is_failure() {
+        stats.vec_txs_error.lock().unwrap().push(format!(
+            "Failed destination {} TxId: {:?} Outcome: {} Reason: {}",
+            dest.url, txid, outcome, reason
+        ));
+    }
+}
+
+///Writes source mempool into a mempool.dat file, layer by layer so parents are always written
+///before their children.
+fn export_mempool_dat(source: &Source, path: &Path, cfg: &Config) -> Result<()> {
+    let source_size = source
+        .client
+        .get_mempool_info()
+        .context(format!("Can't connect to {}", cfg.source_ip_addr))?
+        .size;
+    outln!("\n# Transactions in source mempool: {}", source_size);
+
+    //Entries are kept for the time, fee delta and unbroadcast flag of each tx
+    let entries: HashMap<Txid, GetMempoolEntryResult> = if cfg.fast_mode {
+        source.call(|client| client.get_raw_mempool_verbose())?
+    } else {
+        let txids = get_raw_mempool_sequence(&source.client)?.txids;
+        get_mempool_entries(source, &txids, cfg)
+            .into_iter()
+            .map(|(tx_id, entry)| (*tx_id, entry))
+            .collect()
+    };
+    let vec: Vec<TxDepth> = entries
+        .iter()
+        .map(|(tx_id, entry)| tx_depth(*tx_id, entry))
+        .collect();
+    let vec2 = get_mempool_layers(&vec);
+
+    list_mempool_layers(cfg, &vec2);
+
+    let stats = Stats::default();
+    let mut unbroadcast: Vec<Txid> = vec![];
+    let mut writer = MempoolDatWriter::create(path, cfg.mempool_dat_v1)?;
+
+    let style =
+        ProgressStyle::with_template("{prefix} [{elapsed_precise}] {wide_bar} {pos:>7}/{len:7} ")
+            .unwrap();
+    for (i, txid_vec) in vec2.iter().enumerate() {
+        let pb = ProgressBar::new(txid_vec.len() as u64)
+            .with_style(style.clone())
+            .with_prefix(format!(
+                "Txs depending of {} parents: {}",
+                i,
+                txid_vec.len()
+            ));
+        let txids: Vec<&Txid> = txid_vec.iter().collect();
+        //Chunks keep their order, but it does not matter within a layer.
+        let txs: Vec<(&Txid, String)> = txids
+            .par_chunks(cfg.batch_size as usize)
+            .flat_map_iter(|chunk| {
+                let txs = get_raw_txs_hex(chunk, source, &stats, cfg);
+                pb.inc(chunk.len() as u64);
+                txs
+            })
+            .collect();
+        pb.finish();
+        for (tx_id, tx_hex) in txs {
+            let entry = &entries[tx_id];
+            writer.write_tx(&MempoolDatEntry {
+                raw_tx: hex::decode(tx_hex)?,
+                time: entry.time as i64,
+                fee_delta: entry.fees.modified.to_sat() as i64 - entry.fees.base.to_sat() as i64,
+            })?;
+            if entry.unbroadcast.unwrap_or(false) {
+                unbroadcast.push(*tx_id);
+            }
+        }
+    }
+
+    //Fee deltas of txs not in mempool can't be obtained via rpc.
+    let txs = writer.finish(&[], &unbroadcast)?;
+    outln!("\nWritten {} transactions into {:?}", txs, path);
+
+    if cfg.verbose {
+        stats
+            .vec_txs_error
+            .lock()
+            .unwrap()
+            .iter()
+            .for_each(|err| outln!("{}", err));
+        outln!("\n#Failed queried txs: {:?}", stats.failed_query_txs);
+        outln!("\nFailed queried transactions (if any) are because of transactions removed from mempool while executing this program. i.e. RBF txs");
+    }
+    Ok(())
+}
+
+///Sends the txs in a mempool.dat file to destination, the way bitcoind loads it: fee deltas are
+///applied before sending the txs.
+fn import_mempool_dat(path: &Path, dests: &[Destination], cfg: &Config) -> Result<RunStatus> {
+    let mempool_dat = MempoolDat::read(path)?;
+    for dest in dests {
+        outln!(
+            "\n# (Beginning)\t Transactions in mempool.dat (v{})/destination mempool{}: {}/{}",
+            mempool_dat.version,
+            dest_label(dest, dests),
+            mempool_dat.entries.len(),
+            get_dest_size(dest)?
+        );
+    }
+
+    let stats = Stats::default();
+
+    let deltas: Vec<(Txid, i64)> = mempool_dat
+        .entries
+        .iter()
+        .filter(|(_, entry)| entry.fee_delta != 0)
+        .map(|(txid, entry)| (*txid, entry.fee_delta))
+        .chain(mempool_dat.deltas.iter().copied())
+        .collect();
+    //Fee deltas would change destination mempool.
+    for dest in dests.iter().filter(|_| !cfg.dry_run && !deltas.is_empty()) {
+        //Deltas add up, a tx already in destination may have got its delta already
+        let in_mempool: HashSet<Txid> = dest
+            .call(|client| client.get_raw_mempool())
+            .context(format!("Can't connect to {}", dest.url))?
+            .into_iter()
+            .collect();
+        for (txid, fee_delta) in deltas.iter().filter(|(txid, _)| !in_mempool.contains(txid)) {
+            let res = dest.call(|client| {
+                client.call::<bool>(
+                    "prioritisetransaction",
+                    &[txid.to_string().into(), 0.into(), (*fee_delta).into()],
+                )
+            });
+            if let Err(err) = res {
+                if cfg.verbose {
+                    stats.vec_txs_error.lock().unwrap().push(format!(
+                        "Failed destination {} prioritisetransaction TxId: {:?} Reason: {:?}",
+                        dest.url, txid, err
+                    ));
+                }
+            }
+        }
+    }
+
+    let vec: Vec<TxDepth> = mempool_dat
+        .tx_depths()
+        .into_iter()
+        .map(|(tx_id, depth)| TxDepth {
+            ancestor_count: depth,
+            tx_id,
+            depends: mempool_dat.in_file_parents(&tx_id).collect(),
+            vsize: mempool_dat.vsize(&tx_id),
+            fee: None,
+        })
+        .collect();
+    let vec2 = get_mempool_layers(&vec);
+
+    list_mempool_layers(cfg, &vec2);
+
+    retransmit_rpc_txs(&vec, &mempool_dat, dests, None, &stats, cfg, |_| {});
+
+    print_failed_txs(cfg, &stats, dests);
+
+    if cfg.dry_run {
+        print_dry_run_results(&stats);
+        return Ok(RunStatus::Success);
+    }
+
+    for dest in dests {
+        outln!(
+            "\n# (End)\t\t Transactions in mempool.dat/destination mempool{}: {}/{}",
+            dest_label(dest, dests),
+            mempool_dat.entries.len(),
+            get_dest_size(dest)?
+        );
+    }
+    if !mempool_dat.unbroadcast.is_empty() {
+        outln!(
+            "\nNote: {} transactions were unbroadcast in the file's node, it can't be set via rpc.",
+            mempool_dat.unbroadcast.len()
+        );
+    }
+    Ok(RunStatus::from_stats(&stats))
+}
+
+fn print_failed_txs(cfg: &Config, stats: &Stats, dests: &[Destination]) {
     if cfg.verbose {
-        vec_txs_error
+        stats
+            .vec_txs_error
             .lock()
             .unwrap()
             .iter()
-            .for_each(|err| println!("{}", err));
+            .for_each(|err| outln!("{}", err));
 
-        println!("\n#Failed queried txs: {:?}", failed_query_txs);
-        println!("#Failed sent txs: {:?}", failed_sent_txs);
+        outln!("\n#Failed queried txs: {:?}", stats.failed_query_txs);
+        outln!("#Skipped txs: {:?}", stats.skipped_txs);
+        outln!(
+            "#Txs sent after missing ancestors: {:?}",
+            stats.recovered_txs
+        );
+        outln!("#Failed sent txs: {}", stats.outcomes.failures());
+        outln!("#Sent packages: {:?}", stats.sent_packages);
+        outln!("#Failed sent packages: {:?}", stats.failed_packages);
+
+        outln!("\nFailed queried transactions (if any) are because of transactions removed from mempool while executing this program. i.e. RBF txs");
+        outln!("\nFailed sent transactions missing inputs (if any) are because of parent transaction removed from source mempool while executing this program.");
+    }
+    for dest in dests {
+        print_outcomes(&dest.outcomes, &dest_label(dest, dests));
+        if cfg.verbose {
+            outln!(
+                "#Concurrency limit{}: {}/{}",
+                dest_label(dest, dests),
+                dest.limiter.limit(),
+                dest.limiter.max()
+            );
+            outln!(
+                "#Retried calls{}: {}",
+                dest_label(dest, dests),
+                dest.limiter.retried()
+            );
+        }
+    }
+}
 
-        println!("\nFailed queried transactions (if any) are because of transactions removed from mempool while executing this program. i.e. RBF txs");
-        println!("\nFailed sent transactions (if any) are because of parent transaction removed from mempool while executing this program.");
+fn print_outcomes(outcomes: &OutcomeCounts, label: &str) {
+    let mut outcomes: Vec<(SendOutcome, usize)> = outcomes.non_zero().collect();
+    if outcomes.is_empty() {
+        return;
+    }
+    outcomes.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    outln!("\nDestination{} results:", label);
+    for (outcome, count) in outcomes {
+        outln!("#{}: {}", outcome, count);
     }
 }
 
+//Returns the sizes for each destination.
 fn print_mempool_sizes(
     source_rpc: &Client,
-    dest_rpc: &Client,
+    dests: &[Destination],
     cfg: &Config,
     prefix: &str,
-) -> Result<(), anyhow::Error> {
+) -> Result<Vec<MempoolSizes>> {
     let source_size = source_rpc
         .get_mempool_info()
         .context(format!("Can't connect to {}", cfg.source_ip_addr))?
         .size;
-    let dest_size = dest_rpc
+    METRICS.set_mempool_size(Node::Source, &cfg.source_ip_addr, source_size);
+    let mut sizes = vec![];
+    for dest in dests {
+        let dest_size = get_dest_size(dest)?;
+        METRICS.set_mempool_size(Node::Destination, &dest.url, dest_size);
+        outln!(
+            "\n# {} Transactions in source mempool/destination mempool{}: {}/{} ({} gap)",
+            prefix,
+            dest_label(dest, dests),
+            source_size,
+            dest_size,
+            (source_size as i64 - dest_size as i64).abs()
+        );
+        sizes.push(MempoolSizes {
+            source: source_size,
+            destination: dest_size,
+        });
+    }
+    Ok(sizes)
+}
+
+fn get_dest_size(dest: &Destination) -> Result<usize> {
+    Ok(dest
+        .client
         .get_mempool_info()
-        .context(format!("Can't connect to {}", cfg.dest_ip_addr))?
-        .size;
-    println!(
-        "\n# {} Transactions in source mempool/destination mempool: {}/{} ({} gap)",
-        prefix,
-        source_size,
-        dest_size,
-        (source_size as i64 - dest_size as i64).abs()
-    );
-    Ok(())
+        .context(format!("Can't connect to {}", dest.url))?
+        .size)
+}
+
+//Destination url to add to the output, only needed with several destinations.
+fn dest_label(dest: &Destination, dests: &[Destination]) -> String {
+    if dests.len() > 1 {
+        format!(" {}", dest.url)
+    } else {
+        String::new()
+    }
 }
 
 fn create_spinner() -> ProgressBar {
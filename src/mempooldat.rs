@@ -0,0 +1,345 @@
+use anyhow::{bail, Context, Result};
+use bitcoincore_rpc::bitcoin::consensus::encode::{deserialize, Decodable, Encodable, VarInt};
+use bitcoincore_rpc::bitcoin::{Transaction, Txid};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::path::Path;
+
+//See bitcoin core src/kernel/mempool_persist.cpp
+const MEMPOOL_DUMP_VERSION_NO_XOR_KEY: u64 = 1;
+const MEMPOOL_DUMP_VERSION: u64 = 2;
+
+//Header is version (8 bytes) + xor key (compact size + 8 bytes), then comes the tx count.
+const V1_COUNT_OFFSET: u64 = 8;
+const V2_COUNT_OFFSET: u64 = 8 + 1 + 8;
+
+#[derive(Debug)]
+pub struct MempoolDatEntry {
+    ///Transaction serialized with witness, as given by getrawtransaction.
+    pub raw_tx: Vec<u8>,
+    ///Time the tx entered the mempool, in seconds since epoch.
+    pub time: i64,
+    ///Fee delta set with prioritisetransaction, in satoshis.
+    pub fee_delta: i64,
+}
+
+///Writes a mempool.dat file loadable by `importmempool` or by bitcoind at startup.
+///
+///Transactions must be written parents first, as bitcoind loads them in file order.
+pub struct MempoolDatWriter {
+    file: BufWriter<File>,
+    xor_key: [u8; 8],
+    position: u64,
+    count_offset: u64,
+    count: u64,
+}
+
+impl MempoolDatWriter {
+    ///Creates the file and writes its header. `v1` writes the old non obfuscated format
+    ///(as bitcoind -persistmempoolv1) instead of the XOR obfuscated v2 one.
+    pub fn create(path: &Path, v1: bool) -> Result<Self> {
+        let file = File::create(path).context(format!("Can't create file: {:?}", path))?;
+        let mut writer = MempoolDatWriter {
+            file: BufWriter::new(file),
+            xor_key: [0; 8],
+            position: 0,
+            count_offset: V1_COUNT_OFFSET,
+            count: 0,
+        };
+        if v1 {
+            writer.write(&MEMPOOL_DUMP_VERSION_NO_XOR_KEY.to_le_bytes())?;
+        } else {
+            writer.write(&MEMPOOL_DUMP_VERSION.to_le_bytes())?;
+            let xor_key: [u8; 8] = rand::random();
+            writer.write(&encode(&xor_key.to_vec()))?;
+            writer.xor_key = xor_key;
+            writer.count_offset = V2_COUNT_OFFSET;
+        }
+        //Placeholder, the real count is written by finish.
+        writer.write(&0u64.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    pub fn write_tx(&mut self, entry: &MempoolDatEntry) -> Result<()> {
+        self.write(&entry.raw_tx)?;
+        self.write(&entry.time.to_le_bytes())?;
+        self.write(&entry.fee_delta.to_le_bytes())?;
+        self.count += 1;
+        Ok(())
+    }
+
+    ///Writes the fee deltas of txs not in the mempool and the unbroadcast txids, then fixes
+    ///the tx count in the header. Returns the number of txs written.
+    pub fn finish(mut self, deltas: &[(Txid, i64)], unbroadcast: &[Txid]) -> Result<u64> {
+        let mut buf = encode(&VarInt(deltas.len() as u64));
+        for (txid, delta) in deltas {
+            buf.extend(encode(txid));
+            buf.extend(delta.to_le_bytes());
+        }
+        buf.extend(encode(&VarInt(unbroadcast.len() as u64)));
+        for txid in unbroadcast {
+            buf.extend(encode(txid));
+        }
+        self.write(&buf)?;
+
+        self.file.seek(SeekFrom::Start(self.count_offset))?;
+        self.position = self.count_offset;
+        self.write(&self.count.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(self.count)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut buf = bytes.to_vec();
+        xor(&mut buf, &self.xor_key, self.position);
+        self.file.write_all(&buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+}
+
+///Contents of a mempool.dat file.
+#[derive(Debug)]
+pub struct MempoolDat {
+    pub version: u64,
+    pub entries: HashMap<Txid, MempoolDatEntry>,
+    //Txids spent by the inputs of each tx, to work out dependencies between them.
+    inputs: HashMap<Txid, Vec<Txid>>,
+    ///Fee deltas of txs that were not in the mempool.
+    pub deltas: Vec<(Txid, i64)>,
+    pub unbroadcast: Vec<Txid>,
+}
+
+impl MempoolDat {
+    ///Reads v1 and XOR obfuscated v2 mempool.dat files.
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut buf = std::fs::read(path).context(format!("Can't read file: {:?}", path))?;
+        let mut cursor = Cursor::new(&buf[..]);
+        let version = u64::consensus_decode(&mut cursor)?;
+        let xor_key = match version {
+            MEMPOOL_DUMP_VERSION_NO_XOR_KEY => [0; 8],
+            MEMPOOL_DUMP_VERSION => {
+                let key = Vec::<u8>::consensus_decode(&mut cursor)?;
+                match <[u8; 8]>::try_from(key) {
+                    Ok(key) => key,
+                    Err(key) => bail!("Invalid xor key size in mempool.dat: {}", key.len()),
+                }
+            }
+            _ => bail!("Unknown mempool.dat version: {}", version),
+        };
+        let position = cursor.position();
+        xor(&mut buf[position as usize..], &xor_key, position);
+
+        let mut cursor = Cursor::new(&buf[..]);
+        cursor.set_position(position);
+        let mut entries = HashMap::new();
+        let mut inputs = HashMap::new();
+        let count = u64::consensus_decode(&mut cursor)?;
+        for _ in 0..count {
+            let start = cursor.position() as usize;
+            let tx = Transaction::consensus_decode(&mut cursor)
+                .context("Invalid transaction in mempool.dat")?;
+            let raw_tx = cursor.get_ref()[start..cursor.position() as usize].to_vec();
+            let time = i64::consensus_decode(&mut cursor)?;
+            let fee_delta = i64::consensus_decode(&mut cursor)?;
+            let txid = tx.txid();
+            inputs.insert(
+                txid,
+                tx.input
+                    .iter()
+                    .map(|input| input.previous_output.txid)
+                    .collect(),
+            );
+            entries.insert(
+                txid,
+                MempoolDatEntry {
+                    raw_tx,
+                    time,
+                    fee_delta,
+                },
+            );
+        }
+
+        let mut deltas = vec![];
+        for _ in 0..VarInt::consensus_decode(&mut cursor)?.0 {
+            deltas.push((
+                Txid::consensus_decode(&mut cursor)?,
+                i64::consensus_decode(&mut cursor)?,
+            ));
+        }
+
+        //Files written by bitcoind < 0.21 do not have the unbroadcast set.
+        let mut unbroadcast = vec![];
+        if let Ok(size) = VarInt::consensus_decode(&mut cursor) {
+            for _ in 0..size.0 {
+                unbroadcast.push(Txid::consensus_decode(&mut cursor)?);
+            }
+        }
+
+        Ok(MempoolDat {
+            version,
+            entries,
+            inputs,
+            deltas,
+            unbroadcast,
+        })
+    }
+
+    ///Returns every tx with its number of in-file ancestor generations plus one, which plays the
+    ///role of ancestor_count when building mempool layers: a tx always goes after its parents.
+    pub fn tx_depths(&self) -> Vec<(Txid, usize)> {
+        let mut depths: HashMap<Txid, usize> = HashMap::with_capacity(self.entries.len());
+        for txid in self.entries.keys() {
+            let mut stack = vec![*txid];
+            while let Some(current) = stack.last().copied() {
+                if depths.contains_key(&current) {
+                    stack.pop();
+                    continue;
+                }
+                let parents: Vec<Txid> = self.in_file_parents(&current).collect();
+                let pending: Vec<Txid> = parents
+                    .iter()
+                    .filter(|parent| !depths.contains_key(*parent))
+                    .copied()
+                    .collect();
+                if pending.is_empty() {
+                    let depth = parents
+                        .iter()
+                        .map(|parent| depths[parent])
+                        .max()
+                        .unwrap_or(0);
+                    depths.insert(current, depth + 1);
+                    stack.pop();
+                } else {
+                    stack.extend(pending);
+                }
+            }
+        }
+        depths.into_iter().collect()
+    }
+
+    ///Virtual size of `txid`, 0 if it is not in the file.
+    pub fn vsize(&self, txid: &Txid) -> u64 {
+        self.entries
+            .get(txid)
+            .and_then(|entry| deserialize::<Transaction>(&entry.raw_tx).ok())
+            .map_or(0, |tx| tx.vsize() as u64)
+    }
+
+    ///Parents of `txid` that are also in the file.
+    pub fn in_file_parents<'a>(&'a self, txid: &Txid) -> impl Iterator<Item = Txid> + 'a {
+        self.inputs
+            .get(txid)
+            .into_iter()
+            .flatten()
+            .filter(|parent| self.entries.contains_key(*parent))
+            .copied()
+    }
+}
+
+//Obfuscation key is applied using the absolute position in file, as bitcoind AutoFile does.
+fn xor(buf: &mut [u8], key: &[u8; 8], position: u64) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte ^= key[((position + i as u64) % 8) as usize];
+    }
+}
+
+fn encode<T: Encodable + ?Sized>(value: &T) -> Vec<u8> {
+    let mut buf = vec![];
+    value
+        .consensus_encode(&mut buf)
+        .expect("Writing to a Vec can't fail");
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::absolute::LockTime;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{OutPoint, ScriptBuf, TxIn, TxOut};
+    use std::path::PathBuf;
+
+    fn tx(parent: Txid, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(parent, 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mempoolcp-{}-{}.dat", name, std::process::id()))
+    }
+
+    //Writes a parent and its child, returns the file contents read back and the raw file.
+    fn round_trip(name: &str, v1: bool) -> (MempoolDat, Vec<u8>, Transaction, Transaction) {
+        let parent = tx(Txid::all_zeros(), 50_000);
+        let child = tx(parent.txid(), 40_000);
+        let path = temp_path(name);
+        let mut writer = MempoolDatWriter::create(&path, v1).unwrap();
+        for (tx, time, fee_delta) in [(&parent, 1_700_000_000, 0), (&child, 1_700_000_001, -500)] {
+            writer
+                .write_tx(&MempoolDatEntry {
+                    raw_tx: encode(tx),
+                    time,
+                    fee_delta,
+                })
+                .unwrap();
+        }
+        let deltas = [(Txid::from_byte_array([1; 32]), 1_000)];
+        let count = writer.finish(&deltas, &[child.txid()]).unwrap();
+        assert_eq!(count, 2);
+        let raw = std::fs::read(&path).unwrap();
+        let mempool_dat = MempoolDat::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (mempool_dat, raw, parent, child)
+    }
+
+    fn check_contents(mempool_dat: &MempoolDat, parent: &Transaction, child: &Transaction) {
+        assert_eq!(mempool_dat.entries.len(), 2);
+        let entry = &mempool_dat.entries[&child.txid()];
+        assert_eq!(entry.raw_tx, encode(child));
+        assert_eq!(entry.time, 1_700_000_001);
+        assert_eq!(entry.fee_delta, -500);
+        assert_eq!(
+            mempool_dat.deltas,
+            vec![(Txid::from_byte_array([1; 32]), 1_000)]
+        );
+        assert_eq!(mempool_dat.unbroadcast, vec![child.txid()]);
+        let mut depths = mempool_dat.tx_depths();
+        depths.sort_by_key(|(_, depth)| *depth);
+        assert_eq!(depths, vec![(parent.txid(), 1), (child.txid(), 2)]);
+    }
+
+    #[test]
+    fn round_trips_v1() {
+        let (mempool_dat, raw, parent, child) = round_trip("v1", true);
+        assert_eq!(mempool_dat.version, MEMPOOL_DUMP_VERSION_NO_XOR_KEY);
+        check_contents(&mempool_dat, &parent, &child);
+        let offset = V1_COUNT_OFFSET as usize;
+        assert_eq!(raw[offset..offset + 8], 2u64.to_le_bytes());
+    }
+
+    #[test]
+    fn round_trips_v2() {
+        let (mempool_dat, raw, parent, child) = round_trip("v2", false);
+        assert_eq!(mempool_dat.version, MEMPOOL_DUMP_VERSION);
+        check_contents(&mempool_dat, &parent, &child);
+        //The count is written last, obfuscated with the key at its position in the file
+        assert_eq!(raw[8], 8);
+        let key: [u8; 8] = raw[9..17].try_into().unwrap();
+        let offset = V2_COUNT_OFFSET as usize;
+        let mut count: [u8; 8] = raw[offset..offset + 8].try_into().unwrap();
+        xor(&mut count, &key, V2_COUNT_OFFSET);
+        assert_eq!(count, 2u64.to_le_bytes());
+    }
+}
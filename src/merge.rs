@@ -0,0 +1,338 @@
+use crate::config::Config;
+use crate::source::Source;
+use crate::{get_tx_dept_vec, TxDepth, TxSource};
+use anyhow::{anyhow, Context, Result};
+use bitcoincore_rpc::bitcoin::consensus::encode::deserialize;
+use bitcoincore_rpc::bitcoin::{OutPoint, Transaction, Txid};
+use rayon::iter::ParallelIterator;
+use rayon::slice::ParallelSlice;
+use std::collections::{HashMap, HashSet};
+
+///The union of several source mempools. Each tx is asked to a source node that has it.
+pub struct MergedSource<'a> {
+    pub txs: Vec<TxDepth>,
+    ///Conflicting txs left out, and their descendants
+    pub dropped: usize,
+    //Source node first, then the extra sources
+    sources: Vec<&'a Source>,
+    //Sources having each tx, every source if not found
+    holders: HashMap<Txid, Vec<usize>>,
+    //Txs not in every source, fetched to find conflicts
+    raw_txs: HashMap<Txid, String>,
+}
+
+impl<'a> MergedSource<'a> {
+    ///Copies `txs`, already merged, from `source` and `extra_sources`.
+    pub fn new(source: &'a Source, txs: Vec<TxDepth>, extra_sources: &'a [Source]) -> Self {
+        MergedSource {
+            txs,
+            dropped: 0,
+            sources: std::iter::once(source).chain(extra_sources).collect(),
+            holders: HashMap::new(),
+            raw_txs: HashMap::new(),
+        }
+    }
+
+    fn holders_of(&self, txid: &Txid) -> Vec<usize> {
+        match self.holders.get(txid) {
+            Some(holders) => holders.clone(),
+            None => (0..self.sources.len()).collect(),
+        }
+    }
+}
+
+impl TxSource for MergedSource<'_> {
+    fn get_raw_tx_hex(&self, txid: &Txid) -> Result<String> {
+        if let Some(tx_hex) = self.raw_txs.get(txid) {
+            return Ok(tx_hex.clone());
+        }
+        let mut res = Err(anyhow!("Transaction not found in any source"));
+        for index in self.holders_of(txid) {
+            res = self.sources[index].get_raw_tx_hex(txid);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+
+    fn get_raw_txs_hex(&self, txids: &[&Txid]) -> Vec<Result<String>> {
+        let mut results: Vec<Option<Result<String>>> = txids
+            .iter()
+            .map(|txid| self.raw_txs.get(*txid).cloned().map(Ok))
+            .collect();
+        //The rest are asked together to the first source having them
+        let mut by_source: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (i, txid) in txids.iter().enumerate() {
+            if results[i].is_none() {
+                let index = self.holders_of(txid)[0];
+                by_source.entry(index).or_default().push(i);
+            }
+        }
+        for (index, positions) in by_source {
+            let batch: Vec<&Txid> = positions.iter().map(|i| txids[*i]).collect();
+            let batch_results = self.sources[index].get_raw_txs_hex(&batch);
+            for (i, res) in positions.into_iter().zip(batch_results) {
+                let in_others = self.holders_of(txids[i]).len() > 1;
+                results[i] = Some(match res {
+                    Err(_) if in_others => self.get_raw_tx_hex(txids[i]),
+                    res => res,
+                });
+            }
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn get_mempool_ancestors(&self, txid: &Txid) -> Result<Vec<Txid>> {
+        let mut res = Err(anyhow!("Transaction not found in any source"));
+        for index in self.holders_of(txid) {
+            res = self.sources[index].get_mempool_ancestors(txid);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+}
+
+///Merges `txs`, taken from `source`, with the mempools of `extra_sources`. Of the txs
+///spending the same output, the one with the best mining score is kept, greedily as in a block
+///template.
+pub fn merge<'a>(
+    source: &'a Source,
+    txs: Vec<TxDepth>,
+    extra_sources: &'a [Source],
+    cfg: &Config,
+) -> Result<MergedSource<'a>> {
+    if extra_sources.is_empty() {
+        return Ok(MergedSource::new(source, txs, extra_sources));
+    }
+    let mut merged = MergedSource::new(source, vec![], extra_sources);
+
+    let mut mempools = vec![txs];
+    for source in extra_sources {
+        let (txs, _) =
+            get_tx_dept_vec(source, cfg).context(format!("Can't connect to {}", source.url))?;
+        mempools.push(txs);
+    }
+    let mut entries: HashMap<Txid, TxDepth> = HashMap::new();
+    for (index, txs) in mempools.into_iter().enumerate() {
+        for tx in txs {
+            merged.holders.entry(tx.tx_id).or_default().push(index);
+            entries.entry(tx.tx_id).or_insert(tx);
+        }
+    }
+
+    //A tx in every source can't conflict with any other, only the rest are fetched.
+    let partial: Vec<Txid> = merged
+        .holders
+        .iter()
+        .filter(|(_, holders)| holders.len() < merged.sources.len())
+        .map(|(txid, _)| *txid)
+        .collect();
+    merged.raw_txs = partial
+        .par_chunks(cfg.batch_size as usize)
+        .flat_map_iter(|chunk| {
+            let txids: Vec<&Txid> = chunk.iter().collect();
+            let results = merged.get_raw_txs_hex(&txids);
+            chunk
+                .iter()
+                .zip(results)
+                .filter_map(|(txid, res)| Some((*txid, res.ok()?)))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let removed = drop_conflicts(&entries, &merged.raw_txs);
+    merged.dropped = removed.len();
+    merged.txs = entries
+        .into_values()
+        .filter(|tx| !removed.contains(&tx.tx_id))
+        .collect();
+    Ok(merged)
+}
+
+//Returns the txs losing a conflict and their descendants.
+fn drop_conflicts(
+    entries: &HashMap<Txid, TxDepth>,
+    raw_txs: &HashMap<Txid, String>,
+) -> HashSet<Txid> {
+    let spends: HashMap<Txid, Vec<OutPoint>> = raw_txs
+        .iter()
+        .filter_map(|(txid, tx_hex)| {
+            let tx: Transaction = deserialize(&hex::decode(tx_hex).ok()?).ok()?;
+            let outpoints = tx.input.iter().map(|input| input.previous_output);
+            Some((*txid, outpoints.collect()))
+        })
+        .collect();
+    let mut spenders: HashMap<OutPoint, Vec<Txid>> = HashMap::new();
+    for (txid, outpoints) in &spends {
+        for outpoint in outpoints {
+            spenders.entry(*outpoint).or_default().push(*txid);
+        }
+    }
+    let conflicting: HashSet<Txid> = spenders
+        .into_values()
+        .filter(|txids| txids.len() > 1)
+        .flatten()
+        .collect();
+
+    let mut children: HashMap<Txid, Vec<Txid>> = HashMap::new();
+    for tx in entries.values() {
+        for parent in &tx.depends {
+            children.entry(*parent).or_default().push(tx.tx_id);
+        }
+    }
+
+    let mut candidates: Vec<(f64, Txid)> = conflicting
+        .iter()
+        .map(|txid| (mining_score(txid, entries), *txid))
+        .collect();
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    let mut claimed: HashSet<OutPoint> = HashSet::new();
+    let mut removed: HashSet<Txid> = HashSet::new();
+    for (_, txid) in candidates {
+        if removed.contains(&txid) {
+            continue;
+        }
+        let outpoints = &spends[&txid];
+        if outpoints.iter().any(|outpoint| claimed.contains(outpoint)) {
+            let mut stack = vec![txid];
+            while let Some(txid) = stack.pop() {
+                if removed.insert(txid) {
+                    stack.extend(children.get(&txid).into_iter().flatten());
+                }
+            }
+        } else {
+            claimed.extend(outpoints);
+        }
+    }
+    removed
+}
+
+//Lower of the tx feerate and its ancestors package feerate, in sat/vB.
+fn mining_score(txid: &Txid, entries: &HashMap<Txid, TxDepth>) -> f64 {
+    let feerate = |fee: u64, vsize: u64| fee as f64 / vsize.max(1) as f64;
+    let tx = &entries[txid];
+    let mut ancestors = HashSet::from([*txid]);
+    let mut stack = tx.depends.clone();
+    while let Some(parent) = stack.pop() {
+        if let Some(entry) = entries.get(&parent).filter(|_| ancestors.insert(parent)) {
+            stack.extend(&entry.depends);
+        }
+    }
+    let (fee, vsize) = ancestors
+        .iter()
+        .filter_map(|txid| entries.get(txid))
+        .fold((0, 0), |(fee, vsize), entry| {
+            (fee + entry.fee.unwrap_or(0), vsize + entry.vsize)
+        });
+    feerate(tx.fee.unwrap_or(0), tx.vsize).min(feerate(fee, vsize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::absolute::LockTime;
+    use bitcoincore_rpc::bitcoin::consensus::encode::serialize;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{ScriptBuf, TxIn, TxOut};
+
+    //Output not in the mempools, spent by the conflicting txs
+    fn funding() -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([1; 32]), 0)
+    }
+
+    #[derive(Default)]
+    struct Mempools {
+        entries: HashMap<Txid, TxDepth>,
+        raw_txs: HashMap<Txid, String>,
+    }
+
+    impl Mempools {
+        //Adds a tx of 100 vbytes paying `fee` sats, `value` tells apart txs spending the same.
+        fn add(&mut self, spends: OutPoint, depends: Vec<Txid>, fee: u64, value: u64) -> Txid {
+            let tx = Transaction {
+                version: 2,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: spends,
+                    ..Default::default()
+                }],
+                output: vec![TxOut {
+                    value,
+                    script_pubkey: ScriptBuf::new(),
+                }],
+            };
+            let txid = tx.txid();
+            self.raw_txs.insert(txid, hex::encode(serialize(&tx)));
+            self.entries.insert(
+                txid,
+                TxDepth {
+                    ancestor_count: depends.len() + 1,
+                    tx_id: txid,
+                    depends,
+                    vsize: 100,
+                    fee: Some(fee),
+                },
+            );
+            txid
+        }
+
+        fn drop_conflicts(&self) -> HashSet<Txid> {
+            drop_conflicts(&self.entries, &self.raw_txs)
+        }
+
+        fn mining_score(&self, txid: &Txid) -> f64 {
+            mining_score(txid, &self.entries)
+        }
+    }
+
+    #[test]
+    fn keeps_the_conflicting_tx_with_best_mining_score() {
+        let mut mempools = Mempools::default();
+        let low = mempools.add(funding(), vec![], 1_000, 1);
+        let high = mempools.add(funding(), vec![], 5_000, 2);
+        assert_eq!(mempools.drop_conflicts(), HashSet::from([low]));
+        assert!(mempools.mining_score(&high) > mempools.mining_score(&low));
+    }
+
+    #[test]
+    fn drops_descendants_of_the_losing_tx() {
+        let mut mempools = Mempools::default();
+        let winner = mempools.add(funding(), vec![], 5_000, 1);
+        let loser = mempools.add(funding(), vec![], 1_000, 2);
+        let child = mempools.add(OutPoint::new(loser, 0), vec![loser], 100_000, 3);
+        let grandchild = mempools.add(OutPoint::new(child, 0), vec![child], 1_000, 4);
+        assert_eq!(
+            mempools.drop_conflicts(),
+            HashSet::from([loser, child, grandchild])
+        );
+        assert!(mempools.mining_score(&winner) > mempools.mining_score(&loser));
+    }
+
+    #[test]
+    fn scores_conflicting_txs_with_their_ancestors() {
+        let mut mempools = Mempools::default();
+        //The child pays the most, but its low fee parent drags its score below the other tx
+        let parent = mempools.add(
+            OutPoint::new(Txid::from_byte_array([2; 32]), 0),
+            vec![],
+            100,
+            1,
+        );
+        let child = mempools.add(funding(), vec![parent], 8_000, 2);
+        let other = mempools.add(funding(), vec![], 5_000, 3);
+        assert_eq!(mempools.mining_score(&child), (100 + 8_000) as f64 / 200.0);
+        assert_eq!(mempools.mining_score(&other), 50.0);
+        assert_eq!(mempools.drop_conflicts(), HashSet::from([child]));
+    }
+
+    #[test]
+    fn breaks_score_ties_by_txid() {
+        let mut mempools = Mempools::default();
+        let a = mempools.add(funding(), vec![], 1_000, 1);
+        let b = mempools.add(funding(), vec![], 1_000, 2);
+        assert_eq!(mempools.drop_conflicts(), HashSet::from([a.max(b)]));
+    }
+}
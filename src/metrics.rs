@@ -0,0 +1,324 @@
+use crate::outcome::{OutcomeCounts, SendOutcome};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+//Upper bounds in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+///Counters since program start, served in Prometheus text format with --metrics-listen. They
+///are always updated, as it is cheaper than checking if the endpoint is enabled.
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Node {
+    Source,
+    Destination,
+}
+
+impl Node {
+    fn label(self) -> &'static str {
+        match self {
+            Node::Source => "source",
+            Node::Destination => "destination",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub outcomes: OutcomeCounts,
+    pub failed_query_txs: AtomicUsize,
+    pub zmq_messages: AtomicUsize,
+    ///Times ZMQ messages have been lost
+    pub zmq_gaps: AtomicUsize,
+    ///ZMQ sequence messages received and not processed yet, raw txs are not counted
+    pub zmq_backlog: AtomicUsize,
+    //By node and url
+    mempool_sizes: Mutex<BTreeMap<(Node, String), usize>>,
+    rpc: Mutex<BTreeMap<(Node, String), Histogram>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            outcomes: OutcomeCounts::new(),
+            failed_query_txs: AtomicUsize::new(0),
+            zmq_messages: AtomicUsize::new(0),
+            zmq_gaps: AtomicUsize::new(0),
+            zmq_backlog: AtomicUsize::new(0),
+            mempool_sizes: Mutex::new(BTreeMap::new()),
+            rpc: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    ///Runs `call` recording how long it took in the rpc latency histogram of the node at `url`.
+    pub fn time_rpc<T>(&self, node: Node, url: &str, call: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = call();
+        self.rpc
+            .lock()
+            .unwrap()
+            .entry((node, url.to_string()))
+            .or_insert_with(Histogram::new)
+            .observe(start.elapsed().as_micros() as u64);
+        res
+    }
+
+    pub fn set_mempool_size(&self, node: Node, url: &str, size: usize) {
+        self.mempool_sizes
+            .lock()
+            .unwrap()
+            .insert((node, url.to_string()), size);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicUsize| counter.load(Ordering::SeqCst);
+
+        counter_header(
+            &mut out,
+            "mempoolcp_sent_txs_total",
+            "Txs accepted by destination",
+        );
+        writeln!(
+            out,
+            "mempoolcp_sent_txs_total {}",
+            self.outcomes.get(SendOutcome::Sent)
+        )
+        .unwrap();
+        counter_header(
+            &mut out,
+            "mempoolcp_failed_sent_txs_total",
+            "Txs rejected by destination, not counting the ones already there",
+        );
+        writeln!(
+            out,
+            "mempoolcp_failed_sent_txs_total {}",
+            self.outcomes.failures()
+        )
+        .unwrap();
+        counter_header(
+            &mut out,
+            "mempoolcp_send_outcomes_total",
+            "Txs sent to destination by result",
+        );
+        for outcome in SendOutcome::ALL {
+            writeln!(
+                out,
+                "mempoolcp_send_outcomes_total{{outcome=\"{}\"}} {}",
+                outcome,
+                self.outcomes.get(outcome)
+            )
+            .unwrap();
+        }
+        counter_header(
+            &mut out,
+            "mempoolcp_failed_query_txs_total",
+            "Txs that could not be obtained from source",
+        );
+        writeln!(
+            out,
+            "mempoolcp_failed_query_txs_total {}",
+            load(&self.failed_query_txs)
+        )
+        .unwrap();
+        counter_header(
+            &mut out,
+            "mempoolcp_zmq_messages_total",
+            "ZMQ sequence messages received",
+        );
+        writeln!(
+            out,
+            "mempoolcp_zmq_messages_total {}",
+            load(&self.zmq_messages)
+        )
+        .unwrap();
+        counter_header(
+            &mut out,
+            "mempoolcp_zmq_gaps_total",
+            "Times ZMQ messages have been lost and a resync was needed",
+        );
+        writeln!(out, "mempoolcp_zmq_gaps_total {}", load(&self.zmq_gaps)).unwrap();
+
+        gauge_header(
+            &mut out,
+            "mempoolcp_zmq_backlog",
+            "ZMQ sequence messages received and not processed yet",
+        );
+        writeln!(out, "mempoolcp_zmq_backlog {}", load(&self.zmq_backlog)).unwrap();
+        let mempool_sizes = self.mempool_sizes.lock().unwrap();
+        gauge_header(
+            &mut out,
+            "mempoolcp_mempool_size",
+            "Txs in mempool when last checked",
+        );
+        for ((node, url), size) in mempool_sizes.iter() {
+            writeln!(
+                out,
+                "mempoolcp_mempool_size{{node=\"{}\",url=\"{}\"}} {}",
+                node.label(),
+                url_label(url),
+                size
+            )
+            .unwrap();
+        }
+        //Sizes are only taken of the main source
+        let source_size = mempool_sizes
+            .iter()
+            .find(|((node, _), _)| *node == Node::Source)
+            .map_or(0, |(_, size)| *size);
+        gauge_header(
+            &mut out,
+            "mempoolcp_mempool_gap",
+            "Difference between source and destination mempool sizes",
+        );
+        for ((_, url), dest_size) in mempool_sizes
+            .iter()
+            .filter(|((node, _), _)| *node == Node::Destination)
+        {
+            writeln!(
+                out,
+                "mempoolcp_mempool_gap{{url=\"{}\"}} {}",
+                url_label(url),
+                (source_size as i64 - *dest_size as i64).abs()
+            )
+            .unwrap();
+        }
+        drop(mempool_sizes);
+
+        writeln!(
+            out,
+            "# HELP mempoolcp_rpc_duration_seconds Rpc call latency"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE mempoolcp_rpc_duration_seconds histogram").unwrap();
+        for ((node, url), histogram) in self.rpc.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                &format!("node=\"{}\",url=\"{}\"", node.label(), url_label(url)),
+            );
+        }
+        out
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    //Not cumulative, one more for +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, micros: u64) {
+        let secs = micros as f64 / 1_000_000.0;
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::SeqCst);
+        self.sum_micros.fetch_add(micros, Ordering::SeqCst);
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn render(&self, out: &mut String, labels: &str) {
+        let name = "mempoolcp_rpc_duration_seconds";
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::SeqCst);
+            let bound = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |bound| bound.to_string());
+            writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            )
+            .unwrap();
+        }
+        let sum = self.sum_micros.load(Ordering::SeqCst) as f64 / 1_000_000.0;
+        writeln!(out, "{}_sum{{{}}} {}", name, labels, sum).unwrap();
+        let count = self.count.load(Ordering::SeqCst);
+        writeln!(out, "{}_count{{{}}} {}", name, labels, count).unwrap();
+    }
+}
+
+//Node url without credentials, escaped as a label value.
+fn url_label(url: &str) -> String {
+    let url = match Url::parse(url) {
+        Ok(mut parsed) if !parsed.username().is_empty() || parsed.password().is_some() => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    };
+    url.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn counter_header(out: &mut String, name: &str, help: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name).unwrap();
+}
+
+fn gauge_header(out: &mut String, name: &str, help: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name).unwrap();
+}
+
+//Time given to a scraper to send its request and read the answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_LINE: u64 = 8192;
+
+///Serves `METRICS` on http://`address`/metrics from a background thread.
+pub fn serve(address: SocketAddr) -> Result<()> {
+    let listener =
+        TcpListener::bind(address).context(format!("Can't listen for metrics on {}", address))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            //A slow or misbehaving client should not stop the endpoint.
+            thread::spawn(move || handle_request(stream));
+        }
+    });
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream)
+        .take(MAX_REQUEST_LINE)
+        .read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", METRICS.render())
+    } else {
+        ("404 Not Found", "Not found, try /metrics\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,53 @@
+use crate::batch;
+use crate::limiter::Limiter;
+use crate::metrics::{Node, METRICS};
+use bitcoincore_rpc::{Client, Error};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+///A bitcoind node rpc calls are made to. Calls go through its concurrency limit, are tried again
+///if they fail transiently and are timed in metrics under its url and role.
+pub struct RpcNode {
+    pub url: String,
+    pub client: Client,
+    pub limiter: Limiter,
+    role: Node,
+}
+
+impl RpcNode {
+    pub fn new(
+        role: Node,
+        url: &str,
+        client: Client,
+        concurrency: usize,
+        max_retries: u32,
+    ) -> Self {
+        RpcNode {
+            url: url.to_string(),
+            client,
+            limiter: Limiter::new(concurrency, max_retries),
+            role,
+        }
+    }
+
+    ///Runs `call` within this node concurrency limit, again if the node is throttling.
+    pub fn call<T>(&self, mut call: impl FnMut(&Client) -> Result<T, Error>) -> Result<T, Error> {
+        self.limiter
+            .call(|| METRICS.time_rpc(self.role, &self.url, || call(&self.client)))
+    }
+
+    ///Calls `method` once for each of `params` in a batch request, like [`Self::call`]. Only the
+    ///calls failing transiently are tried again.
+    pub fn call_batch<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: &[Vec<Value>],
+    ) -> Result<Vec<Result<T, Error>>, Error> {
+        self.limiter.call_batch(params.len(), |pending| {
+            let params: Vec<Vec<Value>> = pending.iter().map(|&i| params[i].clone()).collect();
+            METRICS.time_rpc(self.role, &self.url, || {
+                batch::call_batch(&self.client, method, &params)
+            })
+        })
+    }
+}
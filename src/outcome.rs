@@ -0,0 +1,311 @@
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::jsonrpc::simple_http;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//bitcoind rpc error codes
+const RPC_VERIFY_ALREADY_IN_CHAIN: i32 = -27;
+const RPC_IN_WARMUP: i32 = -28;
+
+///What destination node did with a tx sent to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SendOutcome {
+    Sent,
+    AlreadyInMempool,
+    AlreadyInChain,
+    MissingInputs,
+    MinFeeNotMet,
+    MempoolConflict,
+    InsufficientFeeForReplacement,
+    NonStandard,
+    TooLongMempoolChain,
+    MaxFeeExceeded,
+    NonFinal,
+    MempoolFull,
+    Warmup,
+    ///Destination rpc work queue is full (HTTP 503)
+    Throttled,
+    Connection,
+    Other,
+}
+
+impl SendOutcome {
+    pub const ALL: [SendOutcome; 16] = [
+        SendOutcome::Sent,
+        SendOutcome::AlreadyInMempool,
+        SendOutcome::AlreadyInChain,
+        SendOutcome::MissingInputs,
+        SendOutcome::MinFeeNotMet,
+        SendOutcome::MempoolConflict,
+        SendOutcome::InsufficientFeeForReplacement,
+        SendOutcome::NonStandard,
+        SendOutcome::TooLongMempoolChain,
+        SendOutcome::MaxFeeExceeded,
+        SendOutcome::NonFinal,
+        SendOutcome::MempoolFull,
+        SendOutcome::Warmup,
+        SendOutcome::Throttled,
+        SendOutcome::Connection,
+        SendOutcome::Other,
+    ];
+
+    pub fn from_error(err: &bitcoincore_rpc::Error) -> Self {
+        match err {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err)) => match rpc_err.code {
+                RPC_VERIFY_ALREADY_IN_CHAIN => SendOutcome::AlreadyInChain,
+                RPC_IN_WARMUP => SendOutcome::Warmup,
+                _ => SendOutcome::from_reject_reason(&rpc_err.message),
+            },
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(err)) => {
+                match err.downcast_ref::<simple_http::Error>() {
+                    //"Work queue depth exceeded" comes as plain text, not json.
+                    Some(simple_http::Error::HttpErrorCode(503)) => SendOutcome::Throttled,
+                    _ => SendOutcome::Connection,
+                }
+            }
+            bitcoincore_rpc::Error::Io(_) => SendOutcome::Connection,
+            _ => SendOutcome::Other,
+        }
+    }
+
+    ///Whether trying again could work: the node is busy, warming up or could not be reached.
+    ///HTTP client errors, like a wrong password, are not.
+    pub fn is_transient(err: &bitcoincore_rpc::Error) -> bool {
+        match err {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(err)) => !matches!(
+                err.downcast_ref::<simple_http::Error>(),
+                Some(simple_http::Error::HttpErrorCode(code)) if *code < 500
+            ),
+            bitcoincore_rpc::Error::Io(_) => true,
+            err => SendOutcome::from_error(err) == SendOutcome::Warmup,
+        }
+    }
+
+    ///Classifies a bitcoind reject reason, as found in rpc error messages or in
+    ///testmempoolaccept/submitpackage results.
+    pub fn from_reject_reason(reason: &str) -> Self {
+        const NON_STANDARD: [&str; 16] = [
+            "scriptpubkey",
+            "dust",
+            "tx-size",
+            "version",
+            "bare-multisig",
+            "multi-op-return",
+            "scriptsig-size",
+            "scriptsig-not-pushonly",
+            "bad-txns-nonstandard-inputs",
+            "bad-witness-nonstandard",
+            "non-mandatory-script-verify-flag",
+            "tx-size-small",
+            "datacarrier",
+            "TRUC-violation",
+            "v3-rule-violation",
+            "too-many-ephemeral-anchors",
+        ];
+        let has = |pattern: &str| reason.contains(pattern);
+        if has("txn-already-in-mempool")
+            || has("txn-already-known")
+            || has("txn-same-nonwitness-data-in-mempool")
+        {
+            SendOutcome::AlreadyInMempool
+        } else if has("already in block chain") || has("already in utxo set") {
+            SendOutcome::AlreadyInChain
+        } else if has("missingorspent") || has("Missing inputs") || has("missing-inputs") {
+            SendOutcome::MissingInputs
+        } else if has("insufficient fee")
+            || has("replacement-adds-unconfirmed")
+            || has("too many potential replacements")
+        {
+            SendOutcome::InsufficientFeeForReplacement
+        } else if has("min relay fee not met") || has("mempool min fee not met") {
+            SendOutcome::MinFeeNotMet
+        } else if has("txn-mempool-conflict") || has("bad-txns-spends-conflicting-tx") {
+            SendOutcome::MempoolConflict
+        } else if has("too-long-mempool-chain") {
+            SendOutcome::TooLongMempoolChain
+        } else if has("max-fee-exceeded") || has("Fee exceeds maximum") {
+            SendOutcome::MaxFeeExceeded
+        } else if has("non-final") || has("non-BIP68-final") {
+            SendOutcome::NonFinal
+        } else if has("mempool full") {
+            SendOutcome::MempoolFull
+        } else if has("Work queue depth exceeded") {
+            SendOutcome::Throttled
+        } else if NON_STANDARD
+            .iter()
+            .any(|pattern| reason.starts_with(pattern))
+        {
+            SendOutcome::NonStandard
+        } else {
+            SendOutcome::Other
+        }
+    }
+
+    ///Txs already in destination are not failures.
+    pub fn is_failure(&self) -> bool {
+        !matches!(
+            self,
+            SendOutcome::Sent | SendOutcome::AlreadyInMempool | SendOutcome::AlreadyInChain
+        )
+    }
+}
+
+impl fmt::Display for SendOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SendOutcome::Sent => "sent",
+            SendOutcome::AlreadyInMempool => "already-in-mempool",
+            SendOutcome::AlreadyInChain => "already-in-chain",
+            SendOutcome::MissingInputs => "missing-inputs",
+            SendOutcome::MinFeeNotMet => "min-fee-not-met",
+            SendOutcome::MempoolConflict => "mempool-conflict",
+            SendOutcome::InsufficientFeeForReplacement => "insufficient-fee-for-replacement",
+            SendOutcome::NonStandard => "non-standard",
+            SendOutcome::TooLongMempoolChain => "too-long-mempool-chain",
+            SendOutcome::MaxFeeExceeded => "max-fee-exceeded",
+            SendOutcome::NonFinal => "non-final",
+            SendOutcome::MempoolFull => "mempool-full",
+            SendOutcome::Warmup => "warmup",
+            SendOutcome::Throttled => "throttled",
+            SendOutcome::Connection => "connection-error",
+            SendOutcome::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+///Number of txs with each outcome, thread-safe.
+#[derive(Debug, Default)]
+pub struct OutcomeCounts {
+    counts: [AtomicUsize; SendOutcome::ALL.len()],
+}
+
+impl OutcomeCounts {
+    pub const fn new() -> Self {
+        OutcomeCounts {
+            counts: [const { AtomicUsize::new(0) }; SendOutcome::ALL.len()],
+        }
+    }
+
+    pub fn add(&self, outcome: SendOutcome, count: usize) {
+        self.counts[outcome as usize].fetch_add(count, Ordering::SeqCst);
+    }
+
+    pub fn get(&self, outcome: SendOutcome) -> usize {
+        self.counts[outcome as usize].load(Ordering::SeqCst)
+    }
+
+    pub fn failures(&self) -> usize {
+        SendOutcome::ALL
+            .iter()
+            .filter(|outcome| outcome.is_failure())
+            .map(|outcome| self.get(*outcome))
+            .sum()
+    }
+
+    ///Outcomes with at least one tx.
+    pub fn non_zero(&self) -> impl Iterator<Item = (SendOutcome, usize)> + '_ {
+        SendOutcome::ALL
+            .iter()
+            .map(|outcome| (*outcome, self.get(*outcome)))
+            .filter(|(_, count)| *count > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_error(code: i32, message: &str) -> bitcoincore_rpc::Error {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }))
+    }
+
+    fn http_error(code: u16) -> bitcoincore_rpc::Error {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(Box::new(
+            simple_http::Error::HttpErrorCode(code),
+        )))
+    }
+
+    //Reject reasons as bitcoind writes them
+    #[test]
+    fn classifies_bitcoind_reject_reasons() {
+        let cases = [
+            ("min relay fee not met, 100 < 141", SendOutcome::MinFeeNotMet),
+            ("mempool min fee not met, 1000 < 2000", SendOutcome::MinFeeNotMet),
+            ("txn-mempool-conflict", SendOutcome::MempoolConflict),
+            ("bad-txns-inputs-missingorspent", SendOutcome::MissingInputs),
+            (
+                "TRUC-violation, version=3 tx 1234 (wtxid=5678) is too big: 10100 > 10000 virtual bytes",
+                SendOutcome::NonStandard,
+            ),
+            (
+                "insufficient fee, rejecting replacement 1234; new feerate 0.00001 BTC/kvB <= old feerate 0.00002 BTC/kvB",
+                SendOutcome::InsufficientFeeForReplacement,
+            ),
+            (
+                "too-long-mempool-chain, too many unconfirmed ancestors [limit: 25]",
+                SendOutcome::TooLongMempoolChain,
+            ),
+            ("txn-already-in-mempool", SendOutcome::AlreadyInMempool),
+            ("Transaction already in block chain", SendOutcome::AlreadyInChain),
+            ("dust", SendOutcome::NonStandard),
+            ("mempool full", SendOutcome::MempoolFull),
+            ("something new", SendOutcome::Other),
+        ];
+        for (reason, outcome) in cases {
+            assert_eq!(
+                SendOutcome::from_reject_reason(reason),
+                outcome,
+                "{}",
+                reason
+            );
+        }
+    }
+
+    #[test]
+    fn only_node_availability_errors_are_transient() {
+        let cases = [
+            (http_error(503), true),
+            (http_error(500), true),
+            (http_error(401), false),
+            (rpc_error(-28, "Loading block index..."), true),
+            (rpc_error(-26, "min relay fee not met, 100 < 141"), false),
+            (rpc_error(-25, "bad-txns-inputs-missingorspent"), false),
+            (
+                bitcoincore_rpc::Error::Io(std::io::ErrorKind::ConnectionRefused.into()),
+                true,
+            ),
+        ];
+        for (err, transient) in cases {
+            assert_eq!(SendOutcome::is_transient(&err), transient, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn classifies_rpc_errors() {
+        assert_eq!(
+            SendOutcome::from_error(&rpc_error(-27, "Transaction already in block chain")),
+            SendOutcome::AlreadyInChain
+        );
+        assert_eq!(
+            SendOutcome::from_error(&rpc_error(-28, "Loading block index...")),
+            SendOutcome::Warmup
+        );
+        assert_eq!(
+            SendOutcome::from_error(&rpc_error(-26, "mempool min fee not met, 1 < 2")),
+            SendOutcome::MinFeeNotMet
+        );
+        assert_eq!(
+            SendOutcome::from_error(&http_error(503)),
+            SendOutcome::Throttled
+        );
+        assert_eq!(
+            SendOutcome::from_error(&http_error(401)),
+            SendOutcome::Connection
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use crate::TxDepth;
+use bitcoincore_rpc::bitcoin::{Amount, Txid};
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+///bitcoind MAX_PACKAGE_COUNT, also the most txs testmempoolaccept takes
+pub const MAX_PACKAGE_COUNT: usize = 25;
+//bitcoind RPC_METHOD_NOT_FOUND
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+
+#[derive(Debug, Deserialize)]
+pub struct SubmitPackageResult {
+    ///Added in bitcoind 28
+    pub package_msg: Option<String>,
+    #[serde(rename = "tx-results")]
+    pub tx_results: HashMap<String, SubmitPackageTxResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubmitPackageTxResult {
+    pub txid: Txid,
+    pub error: Option<String>,
+}
+
+impl SubmitPackageResult {
+    pub fn is_success(&self) -> bool {
+        self.package_msg
+            .as_deref()
+            .is_none_or(|msg| msg == "success")
+            && self.tx_results.values().all(|res| res.error.is_none())
+    }
+}
+
+///Looks for txs that can't enter a mempool with `min_fee_rate` (per kvB) on their own, but only
+///with the help of a child paying for them (CPFP, TRUC or ephemeral anchor parents). Returns
+///child-with-parents packages, each one with the child last, as `submitpackage` expects.
+pub fn get_packages(vec: &[TxDepth], min_fee_rate: Amount) -> Vec<Vec<Txid>> {
+    let by_txid: HashMap<Txid, &TxDepth> = vec.iter().map(|tx| (tx.tx_id, tx)).collect();
+    let needs_cpfp = |tx: &TxDepth| {
+        tx.fee
+            .is_some_and(|fee| fee * 1000 < min_fee_rate.to_sat() * tx.vsize)
+    };
+
+    let mut candidates: Vec<&TxDepth> = vec
+        .iter()
+        .filter(|tx| tx.fee.is_some() && tx.depends.len() < MAX_PACKAGE_COUNT)
+        .filter(|tx| {
+            tx.depends
+                .iter()
+                .any(|parent| by_txid.get(parent).is_some_and(|parent| needs_cpfp(parent)))
+        })
+        .collect();
+    //Best paying children first
+    candidates.sort_by(|a, b| {
+        let a_rate = a.fee.unwrap() as f64 / a.vsize.max(1) as f64;
+        let b_rate = b.fee.unwrap() as f64 / b.vsize.max(1) as f64;
+        b_rate.total_cmp(&a_rate)
+    });
+
+    let mut grouped: HashSet<Txid> = HashSet::new();
+    let mut packages = vec![];
+    for child in candidates {
+        if grouped.contains(&child.tx_id) {
+            continue;
+        }
+        //Parents already in another package are sent before this one.
+        let parents: Vec<&TxDepth> = child
+            .depends
+            .iter()
+            .filter_map(|parent| by_txid.get(parent).copied())
+            .filter(|parent| !grouped.contains(&parent.tx_id))
+            .collect();
+        if !parents.iter().any(|parent| needs_cpfp(parent)) {
+            continue;
+        }
+        //bitcoind only accepts child-with-parents trees: parents can't depend on each other.
+        let parent_ids: HashSet<Txid> = parents.iter().map(|parent| parent.tx_id).collect();
+        if parents
+            .iter()
+            .any(|parent| parent.depends.iter().any(|p| parent_ids.contains(p)))
+        {
+            continue;
+        }
+        let mut package: Vec<Txid> = parents.iter().map(|parent| parent.tx_id).collect();
+        package.push(child.tx_id);
+        grouped.extend(package.iter().copied());
+        packages.push(package);
+    }
+    packages
+}
+
+pub fn submit_package(
+    dest_client: &Client,
+    txs_hex: Vec<String>,
+) -> Result<SubmitPackageResult, bitcoincore_rpc::Error> {
+    dest_client.call("submitpackage", &[txs_hex.into()])
+}
+
+///True if destination node does not have submitpackage, or only for regtest (bitcoind < 28).
+pub fn is_unsupported(err: &bitcoincore_rpc::Error) -> bool {
+    match err {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err)) => {
+            rpc_err.code == RPC_METHOD_NOT_FOUND || rpc_err.message.contains("regtest")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    const MIN_FEE_RATE: Amount = Amount::from_sat(1_000);
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    //Txs of 100 vbytes paying `fee` sats, 10 sats is below MIN_FEE_RATE
+    fn tx(n: u8, depends: Vec<Txid>, fee: u64) -> TxDepth {
+        TxDepth {
+            ancestor_count: depends.len() + 1,
+            tx_id: txid(n),
+            depends,
+            vsize: 100,
+            fee: Some(fee),
+        }
+    }
+
+    #[test]
+    fn groups_low_fee_parents_with_their_child() {
+        let vec = vec![
+            tx(1, vec![], 10),
+            tx(2, vec![], 10),
+            tx(3, vec![txid(1), txid(2)], 10_000),
+            tx(4, vec![], 500),
+        ];
+        let packages = get_packages(&vec, MIN_FEE_RATE);
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].len(), 3);
+        assert_eq!(packages[0][2], txid(3));
+    }
+
+    #[test]
+    fn leaves_parents_paying_enough_alone() {
+        let vec = vec![tx(1, vec![], 500), tx(2, vec![txid(1)], 10_000)];
+        assert!(get_packages(&vec, MIN_FEE_RATE).is_empty());
+    }
+
+    #[test]
+    fn best_paying_child_takes_a_shared_parent() {
+        let vec = vec![
+            tx(1, vec![], 10),
+            tx(2, vec![txid(1)], 1_000),
+            tx(3, vec![txid(1)], 5_000),
+        ];
+        assert_eq!(
+            get_packages(&vec, MIN_FEE_RATE),
+            vec![vec![txid(1), txid(3)]]
+        );
+    }
+
+    #[test]
+    fn skips_children_with_too_many_parents() {
+        let parents =
+            |count: u8| -> Vec<TxDepth> { (1..=count).map(|n| tx(n, vec![], 10)).collect() };
+        let child = |count: u8| tx(100, (1..=count).map(txid).collect(), 100_000);
+
+        //24 parents and the child fit in a package
+        let mut vec = parents(24);
+        vec.push(child(24));
+        let packages = get_packages(&vec, MIN_FEE_RATE);
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].len(), MAX_PACKAGE_COUNT);
+
+        let mut vec = parents(25);
+        vec.push(child(25));
+        assert!(get_packages(&vec, MIN_FEE_RATE).is_empty());
+    }
+
+    #[test]
+    fn skips_parents_depending_on_each_other() {
+        //2 spends 1, and 3 spends both: not a child-with-parents tree
+        let vec = vec![
+            tx(1, vec![], 10),
+            tx(2, vec![txid(1)], 10),
+            tx(3, vec![txid(1), txid(2)], 10_000),
+        ];
+        let packages = get_packages(&vec, MIN_FEE_RATE);
+        assert!(packages.iter().all(|package| !package.contains(&txid(3))));
+    }
+
+    #[test]
+    fn ignores_txs_without_fee() {
+        let mut child = tx(2, vec![txid(1)], 10_000);
+        child.fee = None;
+        let vec = vec![tx(1, vec![], 10), child];
+        assert!(get_packages(&vec, MIN_FEE_RATE).is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::config::Config;
+use crate::destination::Destination;
+use crate::outcome::{OutcomeCounts, SendOutcome};
+use crate::Stats;
+use anyhow::{Context, Result};
+use bitcoincore_rpc::bitcoin::hashes::{sha256, Hash};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+//Credentials are left out of the report and of the fingerprint.
+const SECRET_FIELDS: [&str; 4] = ["source_user", "source_passwd", "dest_user", "dest_passwd"];
+
+///How a run ended. Its value is the process exit code, after 1 for other errors and 2 for
+///invalid arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunStatus {
+    Success = 0,
+    ///A node could not be reached, or refused the credentials.
+    ConnectionFailure = 3,
+    ///Some txs were rejected by destination.
+    PartialFailure = 4,
+    ///Every tx sent was rejected by destination.
+    TotalFailure = 5,
+}
+
+impl RunStatus {
+    ///Txs already in destination count as copied. In a dry run, txs that would be rejected count
+    ///as failures. If every failure is a node that could not be used, it is a connection failure.
+    pub(crate) fn from_stats(stats: &Stats) -> Self {
+        let rejected: usize = stats.reject_reasons.lock().unwrap().values().sum();
+        let failures = stats.outcomes.failures() + rejected;
+        let unreachable: usize = CONNECTION_OUTCOMES
+            .iter()
+            .map(|outcome| stats.outcomes.get(*outcome))
+            .sum();
+        let total: usize = stats
+            .outcomes
+            .non_zero()
+            .map(|(_, count)| count)
+            .sum::<usize>()
+            + rejected
+            + stats.accepted_txs.load(Ordering::SeqCst);
+        if failures == 0 {
+            RunStatus::Success
+        } else if failures == unreachable {
+            RunStatus::ConnectionFailure
+        } else if failures == total {
+            RunStatus::TotalFailure
+        } else {
+            RunStatus::PartialFailure
+        }
+    }
+}
+
+impl From<RunStatus> for ExitCode {
+    fn from(status: RunStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+//Outcomes of a node that can't be used: unreachable, wrong credentials, warming up or
+//overloaded.
+const CONNECTION_OUTCOMES: [SendOutcome; 3] = [
+    SendOutcome::Connection,
+    SendOutcome::Warmup,
+    SendOutcome::Throttled,
+];
+
+///True if `err` comes from a node that can't be used: unreachable, wrong credentials, warming
+///up or overloaded.
+pub fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<bitcoincore_rpc::Error>()
+            .is_some_and(|err| CONNECTION_OUTCOMES.contains(&SendOutcome::from_error(err)))
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct MempoolSizes {
+    pub source: usize,
+    pub destination: usize,
+}
+
+///What a single destination did with the txs copied.
+#[derive(Debug, Serialize)]
+pub struct DestinationReport {
+    pub url: String,
+    pub outcomes: BTreeMap<String, usize>,
+    pub mempool_size_before: Option<usize>,
+    pub mempool_size_after: Option<usize>,
+    ///Difference between source and destination mempool sizes
+    pub gap_before: Option<i64>,
+    pub gap_after: Option<i64>,
+}
+
+///What destination would do with the txs in a dry run.
+#[derive(Debug, Serialize)]
+pub struct DryRunResults {
+    pub accepted: usize,
+    ///Txs that would be rejected by reject reason
+    pub rejected: BTreeMap<String, usize>,
+    ///Txs not tested, with too many ancestors not in destination for a single testmempoolaccept
+    pub too_large: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Durations {
+    pub total_ms: u128,
+    pub rpc_copy_ms: u128,
+    pub zmq_ms: u128,
+}
+
+///Summary of a copy as a single json document.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    ///Sha256 of `config`
+    pub config_fingerprint: String,
+    pub config: serde_json::Value,
+    pub status: RunStatus,
+    pub exit_code: u8,
+    pub error: Option<String>,
+    pub mempool_sizes_before: Option<MempoolSizes>,
+    pub mempool_sizes_after: Option<MempoolSizes>,
+    ///Number of txs by number of in-mempool ancestors
+    pub layers: Vec<usize>,
+    ///Outcomes of every destination together
+    pub outcomes: BTreeMap<String, usize>,
+    pub destinations: Vec<DestinationReport>,
+    pub failed_query_txs: usize,
+    ///Txs already in every destination, not copied
+    pub skipped_txs: usize,
+    pub zmq_txs: usize,
+    ///Vbytes and base fees of the txs copied from the rpc snapshot
+    pub copied_vbytes: u64,
+    pub copied_fees_sat: u64,
+    pub dry_run: Option<DryRunResults>,
+    pub durations: Durations,
+}
+
+impl Report {
+    pub fn new(cfg: &Config) -> Result<Self> {
+        let mut config = serde_json::to_value(cfg)?;
+        if let Some(fields) = config.as_object_mut() {
+            for field in SECRET_FIELDS {
+                fields.remove(field);
+            }
+            for nodes in ["extra_source", "extra_dest"] {
+                if let Some(nodes) = fields.get_mut(nodes).and_then(|v| v.as_array_mut()) {
+                    for node in nodes.iter_mut().filter_map(|node| node.as_object_mut()) {
+                        node.remove("user");
+                        node.remove("passwd");
+                    }
+                }
+            }
+        }
+        let destinations = cfg
+            .destinations()
+            .into_iter()
+            .map(|dest| DestinationReport {
+                url: dest.url,
+                outcomes: BTreeMap::new(),
+                mempool_size_before: None,
+                mempool_size_after: None,
+                gap_before: None,
+                gap_after: None,
+            })
+            .collect();
+        let config_fingerprint = sha256::Hash::hash(config.to_string().as_bytes()).to_string();
+        Ok(Report {
+            config_fingerprint,
+            config,
+            status: RunStatus::Success,
+            exit_code: 0,
+            error: None,
+            mempool_sizes_before: None,
+            mempool_sizes_after: None,
+            layers: vec![],
+            outcomes: BTreeMap::new(),
+            destinations,
+            failed_query_txs: 0,
+            skipped_txs: 0,
+            zmq_txs: 0,
+            copied_vbytes: 0,
+            copied_fees_sat: 0,
+            dry_run: None,
+            durations: Durations::default(),
+        })
+    }
+
+    pub fn set_status(&mut self, status: RunStatus) {
+        self.status = status;
+        self.exit_code = status as u8;
+    }
+
+    ///Sizes come in destinations order, the first one is the main destination.
+    pub fn set_mempool_sizes_before(&mut self, sizes: Vec<MempoolSizes>) {
+        for (dest, size) in self.destinations.iter_mut().zip(&sizes) {
+            dest.mempool_size_before = Some(size.destination);
+            dest.gap_before = Some(size.source as i64 - size.destination as i64);
+        }
+        self.mempool_sizes_before = sizes.into_iter().next();
+    }
+
+    pub fn set_mempool_sizes_after(&mut self, sizes: Vec<MempoolSizes>) {
+        for (dest, size) in self.destinations.iter_mut().zip(&sizes) {
+            dest.mempool_size_after = Some(size.destination);
+            dest.gap_after = Some(size.source as i64 - size.destination as i64);
+        }
+        self.mempool_sizes_after = sizes.into_iter().next();
+    }
+
+    pub(crate) fn add_stats(&mut self, stats: &Stats, dests: &[Destination]) {
+        self.outcomes = to_map(&stats.outcomes);
+        for (report, dest) in self.destinations.iter_mut().zip(dests) {
+            report.outcomes = to_map(&dest.outcomes);
+        }
+        self.failed_query_txs = stats.failed_query_txs.load(Ordering::SeqCst);
+        self.skipped_txs = stats.skipped_txs.load(Ordering::SeqCst);
+        self.copied_vbytes = stats.copied_vbytes.load(Ordering::SeqCst);
+        self.copied_fees_sat = stats.copied_fees.load(Ordering::SeqCst);
+        self.set_status(RunStatus::from_stats(stats));
+    }
+
+    pub(crate) fn add_dry_run_results(&mut self, stats: &Stats) {
+        self.dry_run = Some(DryRunResults {
+            accepted: stats.accepted_txs.load(Ordering::SeqCst),
+            rejected: stats
+                .reject_reasons
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(reason, count)| (reason.clone(), *count))
+                .collect(),
+            too_large: stats.too_large_txs.load(Ordering::SeqCst),
+        });
+    }
+
+    pub fn set_total_duration(&mut self, duration: Duration) {
+        self.durations.total_ms = duration.as_millis();
+    }
+
+    ///Writes the report into `--report-file`, or into stdout if not set.
+    pub fn write(&self, cfg: &Config) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        match &cfg.report_file {
+            Some(path) => std::fs::write(path, json)
+                .context(format!("Can't write report file: {:?}", path))?,
+            None => println!("{}", json),
+        }
+        Ok(())
+    }
+}
+
+fn to_map(outcomes: &OutcomeCounts) -> BTreeMap<String, usize> {
+    outcomes
+        .non_zero()
+        .map(|(outcome, count)| (outcome.to_string(), count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Outcomes<'a> = &'a [(SendOutcome, usize)];
+
+    fn status(outcomes: Outcomes, rejected: usize) -> RunStatus {
+        let stats = Stats::default();
+        for (outcome, count) in outcomes {
+            stats.outcomes.add(*outcome, *count);
+        }
+        if rejected > 0 {
+            stats
+                .reject_reasons
+                .lock()
+                .unwrap()
+                .insert("min relay fee not met".to_string(), rejected);
+        }
+        RunStatus::from_stats(&stats)
+    }
+
+    #[test]
+    fn maps_outcomes_to_exit_codes() {
+        use SendOutcome::*;
+        let cases: [(Outcomes, usize, RunStatus); 9] = [
+            (&[], 0, RunStatus::Success),
+            (&[(Sent, 3), (AlreadyInMempool, 2)], 0, RunStatus::Success),
+            (&[(Connection, 2)], 0, RunStatus::ConnectionFailure),
+            (
+                &[(Connection, 1), (Warmup, 1)],
+                0,
+                RunStatus::ConnectionFailure,
+            ),
+            (&[(Sent, 5), (Warmup, 1)], 0, RunStatus::ConnectionFailure),
+            (
+                &[(Sent, 5), (MinFeeNotMet, 1)],
+                0,
+                RunStatus::PartialFailure,
+            ),
+            (
+                &[(Connection, 1), (MinFeeNotMet, 1)],
+                0,
+                RunStatus::TotalFailure,
+            ),
+            (
+                &[(MinFeeNotMet, 2), (MempoolConflict, 1)],
+                0,
+                RunStatus::TotalFailure,
+            ),
+            (&[], 2, RunStatus::TotalFailure),
+        ];
+        for (outcomes, rejected, expected) in cases {
+            assert_eq!(status(outcomes, rejected), expected, "{:?}", outcomes);
+        }
+    }
+
+    #[test]
+    fn dry_run_rejects_are_failures() {
+        let stats = Stats::default();
+        stats.accepted_txs.store(3, Ordering::SeqCst);
+        stats
+            .reject_reasons
+            .lock()
+            .unwrap()
+            .insert("dust".to_string(), 1);
+        assert_eq!(RunStatus::from_stats(&stats), RunStatus::PartialFailure);
+        assert_eq!(ExitCode::from(RunStatus::PartialFailure), ExitCode::from(4));
+    }
+}
@@ -0,0 +1,493 @@
+use bitcoincore_rpc::bitcoin::Txid;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+//Live txs accepted but not sent yet, `LiveQueue::push` blocks above it.
+const MAX_LIVE_PENDING: usize = 1_000;
+
+///Dependency graph between the txs to send. Only parents within the graph are taken into
+///account, other parents are expected to be already in the destination or in a block.
+///
+///Each node is a unit sent at once: a single tx or a package (child last).
+#[derive(Debug)]
+pub struct TxGraph {
+    units: Vec<Vec<Txid>>,
+    children: Vec<Vec<usize>>,
+    parents_count: Vec<usize>,
+    unit_of: HashMap<Txid, usize>,
+}
+
+impl TxGraph {
+    ///Builds the graph from (txid, in-mempool parents) pairs, i.e. getmempoolentry `depends`.
+    ///Txs in the same `packages` entry are kept together in one node.
+    pub fn new<'a>(
+        txs: impl Iterator<Item = (Txid, &'a [Txid])> + Clone,
+        packages: Vec<Vec<Txid>>,
+    ) -> Self {
+        let mut packages = packages;
+        loop {
+            let graph = TxGraph::build(txs.clone(), packages.clone());
+            //Grouping txs can create cycles between nodes (package A needs a tx of package B
+            //which needs a tx of A). Those packages are split into single txs, that can't fail.
+            let stuck = graph.stuck_units();
+            if stuck.is_empty() {
+                return graph;
+            }
+            packages = graph
+                .units
+                .into_iter()
+                .enumerate()
+                .filter(|(i, unit)| unit.len() > 1 && !stuck.contains(i))
+                .map(|(_, unit)| unit)
+                .collect();
+        }
+    }
+
+    fn build<'a>(
+        txs: impl Iterator<Item = (Txid, &'a [Txid])> + Clone,
+        packages: Vec<Vec<Txid>>,
+    ) -> Self {
+        let mut units = packages;
+        let mut unit_of: HashMap<Txid, usize> = HashMap::new();
+        for (i, unit) in units.iter().enumerate() {
+            for txid in unit {
+                unit_of.insert(*txid, i);
+            }
+        }
+        for (txid, _) in txs.clone() {
+            if let Entry::Vacant(entry) = unit_of.entry(txid) {
+                entry.insert(units.len());
+                units.push(vec![txid]);
+            }
+        }
+
+        let mut children = vec![vec![]; units.len()];
+        let mut parents_count = vec![0; units.len()];
+        let mut edges: HashSet<(usize, usize)> = HashSet::new();
+        for (txid, depends) in txs {
+            let unit = unit_of[&txid];
+            for parent in depends.iter().filter_map(|parent| unit_of.get(parent)) {
+                if *parent != unit && edges.insert((*parent, unit)) {
+                    children[*parent].push(unit);
+                    parents_count[unit] += 1;
+                }
+            }
+        }
+        TxGraph {
+            units,
+            children,
+            parents_count,
+            unit_of,
+        }
+    }
+
+    //Units that would never be ready because they are in (or after) a cycle.
+    fn stuck_units(&self) -> HashSet<usize> {
+        let mut remaining_parents = self.parents_count.clone();
+        let mut ready: Vec<usize> = (0..self.units.len())
+            .filter(|i| remaining_parents[*i] == 0)
+            .collect();
+        while let Some(i) = ready.pop() {
+            for child in &self.children[i] {
+                remaining_parents[*child] -= 1;
+                if remaining_parents[*child] == 0 {
+                    ready.push(*child);
+                }
+            }
+        }
+        (0..self.units.len())
+            .filter(|i| remaining_parents[*i] > 0)
+            .collect()
+    }
+
+    ///Number of txs in the graph.
+    pub fn len(&self) -> usize {
+        self.unit_of.len()
+    }
+}
+
+struct State<T> {
+    ready: VecDeque<usize>,
+    remaining_parents: Vec<usize>,
+    unit_done: Vec<bool>,
+    done: usize,
+    //Live txs go before graph units
+    live_ready: VecDeque<(Txid, T)>,
+    //Live txs with the number of parents not sent yet
+    live_waiting: HashMap<Txid, (usize, T)>,
+    //Live txs waiting for a tx of the graph or for another live tx
+    live_children: HashMap<Txid, Vec<Txid>>,
+    live_pending: HashSet<Txid>,
+    feeding: bool,
+}
+
+enum Next<T> {
+    Units(Vec<usize>),
+    Live(Txid, T),
+}
+
+impl<T> State<T> {
+    fn release_live_children(&mut self, txid: &Txid) {
+        for child in self.live_children.remove(txid).unwrap_or_default() {
+            if let Entry::Occupied(mut entry) = self.live_waiting.entry(child) {
+                entry.get_mut().0 -= 1;
+                if entry.get().0 == 0 {
+                    let (_, item) = entry.remove();
+                    self.live_ready.push_back((child, item));
+                }
+            }
+        }
+    }
+}
+
+///Txs to send while a graph is being sent, i.e. received from ZMQ.
+pub struct LiveQueue<'a, T> {
+    graph: &'a TxGraph,
+    state: &'a Mutex<State<T>>,
+    cvar: &'a Condvar,
+}
+
+impl<T> LiveQueue<'_, T> {
+    ///Adds a tx to be sent once every one of `parents` in the graph or in the queue has been
+    ///sent. Blocks while there are too many txs pending.
+    pub fn push(&self, txid: Txid, parents: &[Txid], item: T) {
+        let mut st = self.state.lock().unwrap();
+        while st.live_pending.len() >= MAX_LIVE_PENDING {
+            st = self.cvar.wait(st).unwrap();
+        }
+        if !st.live_pending.insert(txid) {
+            return;
+        }
+        let unsent: HashSet<&Txid> = parents
+            .iter()
+            .filter(|parent| {
+                st.live_pending.contains(*parent)
+                    || self
+                        .graph
+                        .unit_of
+                        .get(*parent)
+                        .is_some_and(|unit| !st.unit_done[*unit])
+            })
+            .collect();
+        if unsent.is_empty() {
+            st.live_ready.push_back((txid, item));
+        } else {
+            for parent in &unsent {
+                st.live_children.entry(**parent).or_default().push(txid);
+            }
+            st.live_waiting.insert(txid, (unsent.len(), item));
+        }
+        self.cvar.notify_all();
+    }
+
+    ///True when every unit of the graph has been sent.
+    pub fn graph_done(&self) -> bool {
+        self.state.lock().unwrap().done == self.graph.units.len()
+    }
+}
+
+///Executes `op` for every unit in `graph` using `threads` workers. A unit is handed to a worker
+///as soon as `op` has returned for all of its parents, no matter what happens with the rest of
+///the txs with the same number of ancestors. Each `op` call takes up to `batch_size` units ready
+///at the same time.
+///
+///Meanwhile `feed` runs in its own thread pushing live txs, which are given to `live_op` ahead
+///of the graph units. Returns when both the graph and every live tx pushed have been sent.
+pub fn for_each_in_order<T, F, L, P>(
+    graph: &TxGraph,
+    threads: usize,
+    batch_size: usize,
+    op: F,
+    live_op: L,
+    feed: P,
+) where
+    T: Send,
+    F: Fn(&[&[Txid]]) + Sync,
+    L: Fn(&Txid, T) + Sync,
+    P: FnOnce(&LiveQueue<T>) + Send,
+{
+    let state = Mutex::new(State {
+        ready: (0..graph.units.len())
+            .filter(|i| graph.parents_count[*i] == 0)
+            .collect(),
+        remaining_parents: graph.parents_count.clone(),
+        unit_done: vec![false; graph.units.len()],
+        done: 0,
+        live_ready: VecDeque::new(),
+        live_waiting: HashMap::new(),
+        live_children: HashMap::new(),
+        live_pending: HashSet::new(),
+        feeding: true,
+    });
+    let cvar = Condvar::new();
+    let queue = LiveQueue {
+        graph,
+        state: &state,
+        cvar: &cvar,
+    };
+
+    let threads = threads.max(1);
+    thread::scope(|s| {
+        s.spawn(|| {
+            feed(&queue);
+            state.lock().unwrap().feeding = false;
+            cvar.notify_all();
+        });
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let next = {
+                    let mut st = state.lock().unwrap();
+                    loop {
+                        if let Some((txid, item)) = st.live_ready.pop_front() {
+                            break Some(Next::Live(txid, item));
+                        }
+                        if !st.ready.is_empty() {
+                            //Ready units are shared among the workers
+                            let take = st.ready.len().div_ceil(threads).clamp(1, batch_size);
+                            break Some(Next::Units(st.ready.drain(..take).collect()));
+                        }
+                        if st.done == graph.units.len() && !st.feeding && st.live_pending.is_empty()
+                        {
+                            break None;
+                        }
+                        st = cvar.wait(st).unwrap();
+                    }
+                };
+                let Some(next) = next else {
+                    return;
+                };
+
+                match next {
+                    Next::Units(units) => {
+                        let batch: Vec<&[Txid]> =
+                            units.iter().map(|i| &graph.units[*i][..]).collect();
+                        op(&batch);
+
+                        let mut st = state.lock().unwrap();
+                        for i in units {
+                            for child in &graph.children[i] {
+                                st.remaining_parents[*child] -= 1;
+                                if st.remaining_parents[*child] == 0 {
+                                    st.ready.push_back(*child);
+                                }
+                            }
+                            for txid in &graph.units[i] {
+                                st.release_live_children(txid);
+                            }
+                            st.unit_done[i] = true;
+                            st.done += 1;
+                        }
+                    }
+                    Next::Live(txid, item) => {
+                        live_op(&txid, item);
+
+                        let mut st = state.lock().unwrap();
+                        st.live_pending.remove(&txid);
+                        st.release_live_children(&txid);
+                    }
+                }
+                cvar.notify_all();
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    //Sends every unit of the graph, returns the units in the order they were sent.
+    fn send_order(txs: &[(Txid, Vec<Txid>)], packages: Vec<Vec<Txid>>) -> Vec<Vec<Txid>> {
+        let graph = TxGraph::new(
+            txs.iter().map(|(txid, depends)| (*txid, &depends[..])),
+            packages,
+        );
+        let sent = Mutex::new(vec![]);
+        for_each_in_order(
+            &graph,
+            4,
+            2,
+            |units| {
+                sent.lock()
+                    .unwrap()
+                    .extend(units.iter().map(|unit| unit.to_vec()))
+            },
+            |_: &Txid, _: ()| {},
+            |_| {},
+        );
+        sent.into_inner().unwrap()
+    }
+
+    fn position(order: &[Vec<Txid>], txid: Txid) -> usize {
+        order.iter().position(|unit| unit.contains(&txid)).unwrap()
+    }
+
+    #[test]
+    fn sends_a_chain_in_order() {
+        let txs: Vec<(Txid, Vec<Txid>)> = (1..=10)
+            .map(|n| (txid(n), if n == 1 { vec![] } else { vec![txid(n - 1)] }))
+            .collect();
+        let order = send_order(&txs, vec![]);
+        assert_eq!(order, (1..=10).map(|n| vec![txid(n)]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sends_a_diamond_parents_first() {
+        //1 <- 2, 1 <- 3, (2, 3) <- 4
+        let txs = vec![
+            (txid(4), vec![txid(2), txid(3)]),
+            (txid(2), vec![txid(1)]),
+            (txid(3), vec![txid(1)]),
+            (txid(1), vec![]),
+        ];
+        let order = send_order(&txs, vec![]);
+        assert_eq!(order.len(), 4);
+        assert_eq!(order[0], vec![txid(1)]);
+        assert_eq!(order[3], vec![txid(4)]);
+    }
+
+    #[test]
+    fn ignores_parents_out_of_the_graph() {
+        let txs = vec![(txid(2), vec![txid(1)]), (txid(3), vec![txid(2)])];
+        let order = send_order(&txs, vec![]);
+        assert_eq!(order, vec![vec![txid(2)], vec![txid(3)]]);
+    }
+
+    #[test]
+    fn keeps_packages_together() {
+        //Package (2, 3) needs 1, 4 needs the package
+        let txs = vec![
+            (txid(1), vec![]),
+            (txid(2), vec![txid(1)]),
+            (txid(3), vec![txid(2)]),
+            (txid(4), vec![txid(3)]),
+        ];
+        let order = send_order(&txs, vec![vec![txid(2), txid(3)]]);
+        assert_eq!(
+            order,
+            vec![vec![txid(1)], vec![txid(2), txid(3)], vec![txid(4)]]
+        );
+    }
+
+    #[test]
+    fn splits_packages_in_a_cycle() {
+        //Package (1, 4) and package (2, 3) need each other: 2 spends 1 and 4 spends 3
+        let txs = vec![
+            (txid(1), vec![]),
+            (txid(2), vec![txid(1)]),
+            (txid(3), vec![]),
+            (txid(4), vec![txid(3)]),
+            (txid(5), vec![]),
+            (txid(6), vec![txid(5)]),
+        ];
+        let packages = vec![
+            vec![txid(1), txid(4)],
+            vec![txid(3), txid(2)],
+            vec![txid(5), txid(6)],
+        ];
+        let order = send_order(&txs, packages);
+        assert_eq!(order.len(), 5);
+        assert!(order.contains(&vec![txid(5), txid(6)]));
+        assert!(position(&order, txid(1)) < position(&order, txid(2)));
+        assert!(position(&order, txid(3)) < position(&order, txid(4)));
+    }
+
+    //Spins until `cond` holds, failing the test instead of hanging.
+    fn wait_until(cond: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !cond() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    //Sends `graph` on a single worker while `feed` pushes live txs, returns every txid in the
+    //order it was sent. The graph units wait for the feed to finish so the live txs are pushed
+    //before anything is sent.
+    fn send_order_with_live(
+        graph: &TxGraph,
+        feed: impl FnOnce(&LiveQueue<()>) + Send,
+    ) -> Vec<Txid> {
+        let sent = Mutex::new(vec![]);
+        let fed = AtomicBool::new(false);
+        for_each_in_order(
+            graph,
+            1,
+            1,
+            |units| {
+                wait_until(|| fed.load(Ordering::SeqCst));
+                sent.lock()
+                    .unwrap()
+                    .extend(units.iter().flat_map(|unit| unit.to_vec()))
+            },
+            |txid, _| sent.lock().unwrap().push(*txid),
+            |queue| {
+                feed(queue);
+                fed.store(true, Ordering::SeqCst);
+            },
+        );
+        sent.into_inner().unwrap()
+    }
+
+    #[test]
+    fn holds_live_tx_until_graph_parent_is_sent() {
+        let graph = TxGraph::new([(txid(1), &[][..])].into_iter(), vec![]);
+        let order = send_order_with_live(&graph, |queue| {
+            queue.push(txid(2), &[txid(1)], ());
+        });
+        assert_eq!(order, vec![txid(1), txid(2)]);
+    }
+
+    #[test]
+    fn holds_live_child_until_live_parent_is_sent() {
+        let graph = TxGraph::new([(txid(1), &[][..])].into_iter(), vec![]);
+        let order = send_order_with_live(&graph, |queue| {
+            queue.push(txid(2), &[txid(1)], ());
+            queue.push(txid(3), &[txid(2)], ());
+        });
+        assert_eq!(order, vec![txid(1), txid(2), txid(3)]);
+    }
+
+    #[test]
+    fn blocks_push_above_max_live_pending() {
+        let graph = TxGraph::new(std::iter::empty(), vec![]);
+        let live_txid = |n: usize| {
+            let mut bytes = [0; 32];
+            bytes[..8].copy_from_slice(&n.to_le_bytes());
+            Txid::from_byte_array(bytes)
+        };
+        let pushed = AtomicUsize::new(0);
+        let sent = AtomicUsize::new(0);
+        for_each_in_order(
+            &graph,
+            1,
+            1,
+            |_| {},
+            |_, _: ()| {
+                if sent.fetch_add(1, Ordering::SeqCst) == 0 {
+                    //The first tx is pending while being sent, so the queue fills up and the
+                    //next push blocks until it returns
+                    wait_until(|| pushed.load(Ordering::SeqCst) == MAX_LIVE_PENDING);
+                    thread::sleep(Duration::from_millis(50));
+                    assert_eq!(pushed.load(Ordering::SeqCst), MAX_LIVE_PENDING);
+                }
+            },
+            |queue| {
+                for n in 0..=MAX_LIVE_PENDING {
+                    queue.push(live_txid(n), &[], ());
+                    pushed.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+        );
+        assert_eq!(sent.into_inner(), MAX_LIVE_PENDING + 1);
+    }
+}
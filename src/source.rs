@@ -0,0 +1,69 @@
+use crate::metrics::Node;
+use crate::node::RpcNode;
+use crate::TxSource;
+use anyhow::{anyhow, Result};
+use bitcoincore_rpc::bitcoin::Txid;
+use bitcoincore_rpc::json::GetMempoolEntryResult;
+use bitcoincore_rpc::{Client, RpcApi};
+use std::collections::HashMap;
+use std::ops::Deref;
+
+///A node mempools are read from.
+pub struct Source {
+    node: RpcNode,
+}
+
+impl Source {
+    pub fn new(url: &str, client: Client, max_retries: u32) -> Self {
+        Source {
+            node: RpcNode::new(
+                Node::Source,
+                url,
+                client,
+                rayon::current_num_threads(),
+                max_retries,
+            ),
+        }
+    }
+}
+
+impl Deref for Source {
+    type Target = RpcNode;
+
+    fn deref(&self) -> &RpcNode {
+        &self.node
+    }
+}
+
+impl TxSource for Source {
+    fn get_raw_tx_hex(&self, txid: &Txid) -> Result<String> {
+        Ok(self.call(|client| client.get_raw_transaction_hex(txid, None))?)
+    }
+
+    fn get_raw_txs_hex(&self, txids: &[&Txid]) -> Vec<Result<String>> {
+        let params: Vec<Vec<serde_json::Value>> = txids
+            .iter()
+            .map(|txid| vec![txid.to_string().into()])
+            .collect();
+        match self.call_batch("getrawtransaction", &params) {
+            Ok(results) => results.into_iter().map(|res| Ok(res?)).collect(),
+            Err(err) => {
+                let reason = format!("{:?}", err);
+                txids.iter().map(|_| Err(anyhow!(reason.clone()))).collect()
+            }
+        }
+    }
+
+    fn get_mempool_ancestors(&self, txid: &Txid) -> Result<Vec<Txid>> {
+        let ancestors: HashMap<Txid, GetMempoolEntryResult> = self.call(|client| {
+            client.call(
+                "getmempoolancestors",
+                &[txid.to_string().into(), true.into()],
+            )
+        })?;
+        //A parent always has less ancestors than its children
+        let mut ancestors: Vec<(Txid, GetMempoolEntryResult)> = ancestors.into_iter().collect();
+        ancestors.sort_by_key(|(_, entry)| entry.ancestor_count);
+        Ok(ancestors.into_iter().map(|(txid, _)| txid).collect())
+    }
+}
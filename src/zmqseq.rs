@@ -1,131 +1,932 @@
-use bitcoincore_rpc::bitcoin::hashes::sha256d::Hash;
-use bitcoincore_rpc::bitcoin::Txid;
-use std::str;
-use std::str::FromStr;
+use crate::metrics::METRICS;
+use anyhow::{anyhow, bail, Context, Result};
+use bitcoincore_rpc::bitcoin::consensus::deserialize;
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::{BlockHash, Transaction, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
-use std::sync::mpsc::channel;
-use std::sync::mpsc::Receiver;
-use std::sync::{Arc, Barrier};
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::mpsc::{RecvTimeoutError, TrySendError};
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use url::Url;
 
-#[derive(Debug)]
+const POLL_INTERVAL_MS: i64 = 200;
+const HEARTBEAT_INTERVAL_MS: i32 = 5_000;
+const HEARTBEAT_TIMEOUT_MS: i32 = 15_000;
+//Raw txs kept until forwarded, older ones are asked to source if needed.
+const RAW_TXS_MAX_COUNT: usize = 100_000;
+const RAW_TXS_MAX_BYTES: usize = 64 * 1024 * 1024;
+//Messages received and not yet processed. When full, ZMQ drops new messages and the gap is
+//resynced later.
+const QUEUE_SIZE: usize = 10_000;
+
+#[derive(Debug, PartialEq, Eq)]
 enum MempoolSequence {
-    BlockConnection(String),
-    BlockDisconnection(String),
-    TxRemoved { _txid: String, _seq_num: u64 },
-    TxAdded { txid: String, _seq_num: u64 },
+    BlockConnection(BlockHash),
+    BlockDisconnection(BlockHash),
+    TxRemoved { txid: Txid, seq_num: u64 },
+    TxAdded { txid: Txid, seq_num: u64 },
+}
+
+///Why a ZMQ message could not be understood.
+#[derive(Debug, PartialEq, Eq)]
+enum ParseError {
+    UnexpectedTopic(String),
+    MissingBody,
+    TooShort { len: usize, expected: usize },
+    UnknownLabel(u8),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedTopic(topic) => {
+                write!(f, "topic should be 'sequence' but it's: {:?}", topic)
+            }
+            ParseError::MissingBody => write!(f, "message without body"),
+            ParseError::TooShort { len, expected } => {
+                write!(f, "body has {} bytes, expected {}", len, expected)
+            }
+            ParseError::UnknownLabel(label) => write!(f, "unknown event label: {:#04x}", label),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+//Hash, label and, for tx events, mempool sequence. Extra bytes are ignored.
+const HASH_SIZE: usize = 32;
+const BLOCK_EVENT_SIZE: usize = HASH_SIZE + 1;
+const TX_EVENT_SIZE: usize = BLOCK_EVENT_SIZE + 8;
+
+enum Message {
+    //Event, None if it could not be parsed, with its ZMQ message counter
+    Sequence(Option<MempoolSequence>, Option<u32>),
+    RawTx(Txid, Vec<u8>),
 }
 
 impl TryFrom<&[u8]> for MempoolSequence {
-    type Error = &'static str;
+    type Error = ParseError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        match *value.get(32).unwrap() {
-            // "C"
-            67 => Ok(MempoolSequence::BlockConnection(hex::encode(&value[..32]))),
-            // "D"
-            68 => Ok(MempoolSequence::BlockDisconnection(hex::encode(
-                &value[..32],
-            ))),
-            // "R"
-            82 => Ok(MempoolSequence::TxRemoved {
-                _txid: hex::encode(&value[..32]),
-                _seq_num: u64::from_le_bytes(value[33..41].try_into().expect("Not enough size")),
+        let check_len = |expected: usize| {
+            if value.len() < expected {
+                Err(ParseError::TooShort {
+                    len: value.len(),
+                    expected,
+                })
+            } else {
+                Ok(())
+            }
+        };
+        check_len(BLOCK_EVENT_SIZE)?;
+        //Hashes come in rpc (reversed) byte order
+        let mut hash = [0u8; HASH_SIZE];
+        hash.copy_from_slice(&value[..HASH_SIZE]);
+        hash.reverse();
+        let seq_num = || -> Result<u64, ParseError> {
+            check_len(TX_EVENT_SIZE)?;
+            let mut seq_num = [0u8; 8];
+            seq_num.copy_from_slice(&value[BLOCK_EVENT_SIZE..TX_EVENT_SIZE]);
+            Ok(u64::from_le_bytes(seq_num))
+        };
+        match value[HASH_SIZE] {
+            b'C' => Ok(MempoolSequence::BlockConnection(
+                BlockHash::from_byte_array(hash),
+            )),
+            b'D' => Ok(MempoolSequence::BlockDisconnection(
+                BlockHash::from_byte_array(hash),
+            )),
+            b'R' => Ok(MempoolSequence::TxRemoved {
+                txid: Txid::from_byte_array(hash),
+                seq_num: seq_num()?,
             }),
-            // "A"
-            65 => Ok(MempoolSequence::TxAdded {
-                txid: hex::encode(&value[..32]),
-                _seq_num: u64::from_le_bytes(value[33..41].try_into().expect("Not enough size")),
+            b'A' => Ok(MempoolSequence::TxAdded {
+                txid: Txid::from_byte_array(hash),
+                seq_num: seq_num()?,
             }),
-            _ => Err("Invalid char code in message"),
+            label => Err(ParseError::UnknownLabel(label)),
         }
     }
 }
 
-// pub fn into_arr4<T>(v: Vec<T>) -> [T; 4] {
-//     let boxed_slice = v.into_boxed_slice();
-//     let boxed_array: Box<[T; 4]> = match boxed_slice.try_into() {
-//         Ok(ba) => ba,
-//         Err(o) => panic!("Expected a Vec of length {} but it was {}", 4, o.len()),
-//     };
-//     *boxed_array
-// }
-pub struct ZmqThread {
-    rx: Receiver<String>,
-    stop: Arc<AtomicBool>,
-    thread: JoinHandle<()>,
+//Txs announced via ZMQ and not yet forwarded. Txs removed from source mempool before being
+//forwarded are dropped.
+#[derive(Debug, Default)]
+struct Pending {
+    //Txids in arrival order with the mempool sequence of their addition, an entry is stale if
+    //the txid was removed (and maybe added again) afterwards.
+    queue: VecDeque<(Txid, u64)>,
+    //Txs of disconnected blocks back in the mempool. They go before the rest as older mempool
+    //txs can depend on them.
+    resurrected: VecDeque<(Txid, u64)>,
+    live: HashMap<Txid, u64>,
+    //Txs of disconnected blocks not yet back in the mempool
+    disconnected: HashSet<Txid>,
+    //Events before the rpc snapshot sequence are already in it.
+    snapshot_sequence: u64,
+    last_sequence: Option<u64>,
+    //Txs removed by a block do not have an event, but consume mempool sequence numbers.
+    block_since_last: bool,
+    //Txs conflicting with a block are removed before its C event, so a jump on an R event is a
+    //gap only if the next event is not the block.
+    removal_jump: bool,
 }
 
-impl ZmqThread {
-    pub fn spawn(zmq_address: &Url) -> Self {
+impl Pending {
+    //Returns false if some event has been lost.
+    fn apply(&mut self, event: MempoolSequence, source_client: &Client) -> bool {
+        match event {
+            MempoolSequence::TxAdded { txid, seq_num } => {
+                if seq_num < self.snapshot_sequence {
+                    return true;
+                }
+                let gapless = self.check_sequence(seq_num, false);
+                if self.live.contains_key(&txid) {
+                    return gapless;
+                }
+                self.live.insert(txid, seq_num);
+                if self.disconnected.remove(&txid) {
+                    self.resurrected.push_back((txid, seq_num));
+                } else {
+                    self.queue.push_back((txid, seq_num));
+                }
+                gapless
+            }
+            MempoolSequence::TxRemoved { txid, seq_num } => {
+                if seq_num < self.snapshot_sequence {
+                    return true;
+                }
+                self.live.remove(&txid);
+                self.check_sequence(seq_num, true)
+            }
+            //Confirmed txs are not notified as removed, the block has to be asked for.
+            MempoolSequence::BlockConnection(hash) => {
+                for txid in block_txids(&hash, source_client) {
+                    self.live.remove(&txid);
+                    self.disconnected.remove(&txid);
+                }
+                self.block_since_last = true;
+                self.removal_jump = false;
+                true
+            }
+            MempoolSequence::BlockDisconnection(hash) => {
+                //Coinbase never goes back to the mempool
+                self.disconnected
+                    .extend(block_txids(&hash, source_client).into_iter().skip(1));
+                self.block_since_last = true;
+                self.removal_jump = false;
+                true
+            }
+        }
+    }
+
+    fn check_sequence(&mut self, seq_num: u64, removal: bool) -> bool {
+        let jump = match self.last_sequence {
+            Some(last) => seq_num > last + 1 && !self.block_since_last,
+            None => false,
+        };
+        self.last_sequence = Some(self.last_sequence.map_or(seq_num, |last| last.max(seq_num)));
+        self.block_since_last = false;
+        if removal {
+            self.removal_jump |= jump;
+            true
+        } else {
+            let gapless = !jump && !self.removal_jump;
+            self.removal_jump = false;
+            gapless
+        }
+    }
+
+    //`sequence` is the mempool sequence of a getrawmempool call: txs added before are already
+    //handled, and the next event should have this sequence.
+    fn set_snapshot_sequence(&mut self, sequence: u64) {
+        self.snapshot_sequence = sequence;
+        self.live.retain(|_, seq_num| *seq_num >= sequence);
+        if self.last_sequence.is_none_or(|last| last + 1 < sequence) {
+            self.last_sequence = sequence.checked_sub(1);
+            self.block_since_last = false;
+            self.removal_jump = false;
+        }
+    }
+
+    fn pop(&mut self) -> Option<Txid> {
+        while let Some((txid, generation)) = self
+            .resurrected
+            .pop_front()
+            .or_else(|| self.queue.pop_front())
+        {
+            if self.live.get(&txid) == Some(&generation) {
+                self.live.remove(&txid);
+                return Some(txid);
+            }
+        }
+        None
+    }
+}
+
+//Raw txs received from the rawtx topic, so they don't have to be asked to source. Txs in
+//connected blocks are published too, they are never taken and end up dropped as the oldest.
+#[derive(Debug, Default)]
+struct RawTxs {
+    txs: HashMap<Txid, Vec<u8>>,
+    //Insertion order, may contain already taken txids
+    order: VecDeque<Txid>,
+    bytes: usize,
+}
+
+impl RawTxs {
+    fn insert(&mut self, txid: Txid, raw_tx: Vec<u8>) {
+        self.bytes += raw_tx.len();
+        match self.txs.insert(txid, raw_tx) {
+            Some(old) => self.bytes -= old.len(),
+            None => self.order.push_back(txid),
+        }
+        while self.bytes > RAW_TXS_MAX_BYTES || self.order.len() > RAW_TXS_MAX_COUNT {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(raw_tx) = self.txs.remove(&oldest) {
+                self.bytes -= raw_tx.len();
+            }
+        }
+    }
+
+    fn take(&mut self, txid: &Txid) -> Option<Vec<u8>> {
+        let raw_tx = self.txs.remove(txid)?;
+        self.bytes -= raw_tx.len();
+        Some(raw_tx)
+    }
+}
+
+//If the block can't be obtained its txs are forwarded anyway, destination will reject them.
+fn block_txids(hash: &BlockHash, source_client: &Client) -> Vec<Txid> {
+    source_client
+        .get_block_info(hash)
+        .map(|block| block.tx)
+        .unwrap_or_default()
+}
+
+struct Subscriber {
+    socket: zmq::Socket,
+    //Receives socket connection events
+    monitor: zmq::Socket,
+    address: Url,
+}
+
+impl Subscriber {
+    //`hint` tells how to fix a connection failure.
+    fn connect(address: &Url, topics: &[&str], timeout: Duration, hint: &str) -> Result<Self> {
         let context = zmq::Context::new();
-        let subscriber = context.socket(zmq::SUB).unwrap();
-        subscriber
-            .connect(zmq_address.as_str())
-            .expect("Cannot connect to publisher");
-        subscriber
-            .set_subscribe(b"sequence")
-            .expect("Failed subscribing.");
-        let stop_th = Arc::new(AtomicBool::new(false));
-        let stop = stop_th.clone();
-        let (tx, rx) = channel();
-        //Use a barrier, the 'loadmempool' phase should execute after this method to not
-        //loose any tx at the cost of loading/receiving twice some txs.
-        let barrier = Arc::new(Barrier::new(2));
-
-        let barrierc = barrier.clone();
-        let thread = thread::spawn(move || {
-            let mut wait = true;
-            while !stop_th.load(Ordering::SeqCst) {
-                let msg = subscriber.recv_multipart(0).unwrap();
-                if wait {
-                    barrier.wait();
-                    wait = false;
+        let socket = context.socket(zmq::SUB)?;
+        //Dead connections are detected with heartbeats, then libzmq reconnects by itself.
+        socket.set_heartbeat_ivl(HEARTBEAT_INTERVAL_MS)?;
+        socket.set_heartbeat_timeout(HEARTBEAT_TIMEOUT_MS)?;
+        socket.set_linger(0)?;
+        let monitor_endpoint = "inproc://mempoolcp-zmq-monitor";
+        socket.monitor(
+            monitor_endpoint,
+            zmq::SocketEvent::CONNECTED as i32 | zmq::SocketEvent::DISCONNECTED as i32,
+        )?;
+        let monitor = context.socket(zmq::PAIR)?;
+        monitor.connect(monitor_endpoint)?;
+        socket
+            .connect(address.as_str())
+            .context(format!("Invalid ZMQ address: {}", address))?;
+        for topic in topics {
+            socket.set_subscribe(topic.as_bytes())?;
+        }
+        let subscriber = Subscriber {
+            socket,
+            monitor,
+            address: address.clone(),
+        };
+
+        //ZMQ connects lazily and retries forever, a wrong address would not fail otherwise.
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero()
+                || subscriber
+                    .monitor
+                    .poll(zmq::POLLIN, remaining.as_millis() as i64)?
+                    == 0
+            {
+                bail!(
+                    "Can't connect to ZMQ publisher {} in {}s, {}",
+                    address,
+                    timeout.as_secs(),
+                    hint
+                );
+            }
+            if subscriber.monitor_event()? == zmq::SocketEvent::CONNECTED {
+                return Ok(subscriber);
+            }
+        }
+    }
+
+    //Monitor messages are an u16 event id plus an u32 value, then the endpoint.
+    fn monitor_event(&self) -> Result<zmq::SocketEvent> {
+        let msg = self.monitor.recv_multipart(0)?;
+        match msg.first().and_then(|frame| frame.get(..2)) {
+            Some(event) => Ok(zmq::SocketEvent::from_raw(u16::from_le_bytes([
+                event[0], event[1],
+            ]))),
+            None => bail!("Invalid ZMQ monitor message"),
+        }
+    }
+
+    //Sends received events until `stop` is set or the receiver is dropped. The stop flag is
+    //checked at least every POLL_INTERVAL_MS even if nothing is received or the queue is full.
+    fn listen(self, tx: &SyncSender<Message>, stop: &AtomicBool) -> Result<()> {
+        while !stop.load(Ordering::SeqCst) {
+            let mut items = [
+                self.socket.as_poll_item(zmq::POLLIN),
+                self.monitor.as_poll_item(zmq::POLLIN),
+            ];
+            zmq::poll(&mut items, POLL_INTERVAL_MS)?;
+            if items[1].is_readable() {
+                match self.monitor_event()? {
+                    zmq::SocketEvent::DISCONNECTED => eprintln!(
+                        "ZMQ publisher {} disconnected, reconnecting...",
+                        self.address
+                    ),
+                    zmq::SocketEvent::CONNECTED => {
+                        eprintln!("ZMQ publisher {} reconnected", self.address)
+                    }
+                    _ => {}
                 }
-                let topic = str::from_utf8(msg.get(0).unwrap()).expect("Cannot unwrap topic");
-                if topic.ne("sequence") {
-                    panic!("ZMQ topic should be 'sequence' but it's: {}", topic);
+            }
+            if !items[0].is_readable() {
+                continue;
+            }
+            let msg = self.socket.recv_multipart(0)?;
+            let message = if msg.first().is_some_and(|topic| topic == b"rawtx") {
+                match parse_raw_tx(&msg) {
+                    Some((txid, raw_tx)) => Message::RawTx(txid, raw_tx),
+                    //The tx will be asked to source
+                    None => continue,
                 }
-                let body = msg.get(1).unwrap();
-                let mpsq = MempoolSequence::try_from(&body[..]).unwrap();
-                // println!("{:?}", mpsq);
-                // let seq = u32::from_le_bytes(into_arr4(msg.get(2).unwrap().to_vec()));
-                // println!("Seq: {}", seq);
-                match mpsq {
-                    MempoolSequence::TxAdded {
-                        txid: tx_id,
-                        _seq_num: _,
-                    } => tx.send(tx_id).unwrap(),
-                    _ => (),
+            } else {
+                METRICS.zmq_messages.fetch_add(1, Ordering::SeqCst);
+                METRICS.zmq_backlog.fetch_add(1, Ordering::SeqCst);
+                //Malformed messages still go through to keep the message counter gapless.
+                let event = parse_message(&msg)
+                    .inspect_err(|err| eprintln!("Skipping malformed ZMQ message: {}", err))
+                    .ok();
+                Message::Sequence(event, message_counter(&msg))
+            };
+            let mut message = message;
+            loop {
+                match tx.try_send(message) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(unsent)) if !stop.load(Ordering::SeqCst) => {
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS as u64 / 10));
+                        message = unsent;
+                    }
+                    Err(_) => return Ok(()),
                 }
             }
+        }
+        Ok(())
+    }
+}
+
+//Messages have topic, body and message counter frames.
+fn parse_message(msg: &[Vec<u8>]) -> Result<MempoolSequence, ParseError> {
+    let topic = msg.first().map(|topic| String::from_utf8_lossy(topic));
+    if topic.as_deref() != Some("sequence") {
+        return Err(ParseError::UnexpectedTopic(
+            topic.unwrap_or_default().into_owned(),
+        ));
+    }
+    let body = msg.get(1).ok_or(ParseError::MissingBody)?;
+    MempoolSequence::try_from(&body[..])
+}
+
+fn parse_raw_tx(msg: &[Vec<u8>]) -> Option<(Txid, Vec<u8>)> {
+    let raw_tx = msg.get(1)?;
+    match deserialize::<Transaction>(raw_tx) {
+        Ok(tx) => Some((tx.txid(), raw_tx.clone())),
+        Err(err) => {
+            eprintln!("Skipping malformed ZMQ rawtx message: {}", err);
+            None
+        }
+    }
+}
+
+fn message_counter(msg: &[Vec<u8>]) -> Option<u32> {
+    msg.get(2)
+        .and_then(|counter| <[u8; 4]>::try_from(&counter[..]).ok())
+        .map(u32::from_le_bytes)
+}
+
+//getzmqnotifications result entry
+#[derive(Debug, Deserialize)]
+struct ZmqNotification {
+    #[serde(rename = "type")]
+    kind: String,
+    address: String,
+}
+
+///Finds the ZMQ sequence publisher of source node, and its raw tx publisher if it is on a
+///different address. Wildcard bind addresses are replaced by the host of `source_url`.
+pub fn discover(source_client: &Client, source_url: &str) -> Result<(Url, Option<Url>)> {
+    let notifications: Vec<ZmqNotification> = source_client
+        .call("getzmqnotifications", &[])
+        .context("Can't get ZMQ interfaces of source node")?;
+    let address_of = |kind: &str| {
+        notifications
+            .iter()
+            .find(|notification| notification.kind == kind)
+            .map(|notification| notification.address.as_str())
+    };
+    let Some(sequence_address) = address_of("pubsequence") else {
+        bail!("Source node does not publish ZMQ sequence notifications, add zmqpubsequence=tcp://<ip>:<port> to its bitcoin.conf or use --zmq-address");
+    };
+    //The rpc client takes urls without scheme as http ones
+    let source_host = if source_url.contains("://") {
+        Url::parse(source_url)
+    } else {
+        Url::parse(&format!("http://{}", source_url))
+    }
+    .ok()
+    .and_then(|url| url.host_str().map(str::to_string));
+    let rawtx_address = match address_of("pubrawtx") {
+        Some(address) if address != sequence_address => {
+            Some(connectable_address(address, &source_host)?)
+        }
+        _ => None,
+    };
+    Ok((
+        connectable_address(sequence_address, &source_host)?,
+        rawtx_address,
+    ))
+}
+
+//Bind addresses like tcp://0.0.0.0:28332 or tcp://*:28332 are not valid to connect to.
+fn connectable_address(address: &str, source_host: &Option<String>) -> Result<Url> {
+    let fixed = match address
+        .strip_prefix("tcp://")
+        .and_then(|endpoint| endpoint.rsplit_once(':'))
+    {
+        Some(("*" | "0.0.0.0" | "[::]" | "::", port)) => match source_host {
+            Some(source_host) => format!("tcp://{}:{}", source_host, port),
+            None => bail!(
+                "Source node ZMQ interface listens on {}, but source url has no host to connect to, use --zmq-address",
+                address
+            ),
+        },
+        _ => address.to_string(),
+    };
+    Url::parse(&fixed).context(format!("Invalid ZMQ address in source node: {}", address))
+}
+
+pub struct ZmqThread {
+    rx: Receiver<Message>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>,
+    //Listens to --zmq-rawtx-address
+    rawtx_thread: Option<JoinHandle<()>>,
+    pending: Pending,
+    raw_txs: RawTxs,
+    last_counter: Option<u32>,
+    //Messages were lost while streaming, resync in the next `for_each` or `for_each_until`.
+    deferred_gap: bool,
+}
+
+impl ZmqThread {
+    ///Connects to the ZMQ publisher and starts listening. Raw txs are received from
+    ///`rawtx_address` if set, or else from `zmq_address` if it publishes them. Fails if it can't
+    ///connect within `timeout`.
+    pub fn spawn(
+        zmq_address: &Url,
+        rawtx_address: Option<&Url>,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let topics: &[&str] = match rawtx_address {
+            Some(_) => &["sequence"],
+            None => &["sequence", "rawtx"],
+        };
+        let subscriber = Subscriber::connect(
+            zmq_address,
+            topics,
+            timeout,
+            "check --zmq-address and zmqpubsequence in source bitcoin.conf",
+        )?;
+        let rawtx_subscriber = rawtx_address
+            .map(|address| {
+                Subscriber::connect(
+                    address,
+                    &["rawtx"],
+                    timeout,
+                    "check --zmq-rawtx-address and zmqpubrawtx in source bitcoin.conf",
+                )
+            })
+            .transpose()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let rawtx_thread = rawtx_subscriber.map(|subscriber| {
+            let (tx, stop) = (tx.clone(), stop.clone());
+            thread::spawn(move || {
+                if let Err(err) = subscriber.listen(&tx, &stop) {
+                    eprintln!(
+                        "ZMQ rawtx interface error, txs will be asked to source: {:?}",
+                        err
+                    );
+                }
+            })
+        });
+        //Events already in the rpc snapshot are discarded using the mempool sequence, and
+        //events lost before subscription is ready are detected as a gap.
+        let stop_th = stop.clone();
+        let thread = thread::spawn(move || {
+            let res = subscriber.listen(&tx, &stop_th);
+            //Without sequence events raw txs are useless, the channel gets disconnected.
+            stop_th.store(true, Ordering::SeqCst);
+            res
         });
-        barrierc.wait();
-        ZmqThread { rx, stop, thread }
+        Ok(ZmqThread {
+            rx,
+            stop,
+            thread: Some(thread),
+            rawtx_thread,
+            pending: Pending::default(),
+            raw_txs: RawTxs::default(),
+            last_counter: None,
+            deferred_gap: false,
+        })
+    }
+
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(Ok(Err(err))) = self.thread.take().map(|thread| thread.join()) {
+            eprintln!("ZMQ interface error: {:?}", err);
+        }
+        if let Some(thread) = self.rawtx_thread.take() {
+            let _ = thread.join();
+        }
+        //Messages left in the channel are not processed
+        METRICS.zmq_backlog.store(0, Ordering::SeqCst);
+    }
+
+    //Error that made the thread stop.
+    fn thread_error(&mut self) -> anyhow::Error {
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(Err(err))) => err.context("ZMQ thread has stopped"),
+            _ => anyhow!("ZMQ thread has stopped"),
+        }
+    }
+
+    ///Sets the mempool sequence of the last rpc copy (getrawmempool with mempool_sequence), only
+    ///the events after it are replayed.
+    pub fn set_snapshot_sequence(&mut self, sequence: u64) {
+        self.pending.set_snapshot_sequence(sequence);
+    }
+
+    //Returns false if a ZMQ message or a mempool event has been lost.
+    fn receive(&mut self, message: Message, source_client: &Client) -> bool {
+        let (event, counter) = match message {
+            Message::Sequence(event, counter) => (event, counter),
+            Message::RawTx(txid, raw_tx) => {
+                self.raw_txs.insert(txid, raw_tx);
+                return true;
+            }
+        };
+        METRICS.zmq_backlog.fetch_sub(1, Ordering::SeqCst);
+        let mut gapless = true;
+        if let Some(counter) = counter {
+            //Counter is reset if the publisher restarts
+            gapless = self
+                .last_counter
+                .is_none_or(|last| counter == last.wrapping_add(1));
+            self.last_counter = Some(counter);
+        }
+        match event {
+            Some(event) => self.pending.apply(event, source_client) && gapless,
+            None => gapless,
+        }
+    }
+
+    //Applies every event already received, without waiting. Runs `resync` if something was
+    //lost now or while streaming.
+    fn receive_all<R>(&mut self, source_client: &Client, resync: &R)
+    where
+        R: Fn() -> Result<u64>,
+    {
+        let mut gapless = !std::mem::take(&mut self.deferred_gap);
+        while let Ok(message) = self.rx.try_recv() {
+            gapless &= self.receive(message, source_client);
+        }
+        if !gapless {
+            self.resync(resync);
+        }
+    }
+
+    fn resync<R>(&mut self, resync: &R)
+    where
+        R: Fn() -> Result<u64>,
+    {
+        METRICS.zmq_gaps.fetch_add(1, Ordering::SeqCst);
+        match resync() {
+            Ok(sequence) => self.set_snapshot_sequence(sequence),
+            //Next reconciliation pass or the next gap will try again
+            Err(err) => eprintln!("Resync after lost ZMQ messages failed: {:?}", err),
+        }
     }
 
-    pub fn for_each<F>(self, op: F) -> usize
+    ///Executes `op` for every tx received and still in source mempool, in arrival order, then
+    ///stops the thread. `op` is given the raw tx if it was received from the rawtx topic.
+    ///`source_client` is used to know the txs in connected/disconnected blocks.
+    ///
+    ///If a message is lost `resync` is executed, it must send the source txs missing in
+    ///destination and return the mempool sequence it was based on.
+    pub fn for_each<F, R>(mut self, source_client: &Client, op: F, resync: R) -> usize
     where
-        F: Fn(&Txid),
+        F: Fn(&Txid, Option<Vec<u8>>),
+        R: Fn() -> Result<u64>,
     {
         let mut counter = 0;
         loop {
-            match self.rx.try_iter().next() {
+            self.receive_all(source_client, &resync);
+            match self.pending.pop() {
                 Some(tx_id) => {
-                    op(&Txid::from(Hash::from_str(tx_id.as_str()).unwrap()));
+                    op(&tx_id, self.raw_txs.take(&tx_id));
                     counter += 1;
                 }
-                None => {
-                    self.stop.store(true, Ordering::SeqCst);
-                    break;
-                }
+                None => break,
             }
         }
-        self.thread.join().expect("Error in tread");
+        self.stop();
         counter
     }
+
+    ///Executes `op` for every tx received and still in source mempool until `done` returns true,
+    ///i.e. while a rpc copy is running. Lost messages are resynced by the next `for_each` or
+    ///`for_each_until` call, so the running copy is not repeated meanwhile.
+    pub fn stream_until<F, D>(&mut self, source_client: &Client, done: D, op: F) -> Result<usize>
+    where
+        F: Fn(&Txid, Option<Vec<u8>>),
+        D: Fn() -> bool,
+    {
+        let mut counter = 0;
+        while !done() {
+            while let Ok(message) = self.rx.try_recv() {
+                self.deferred_gap |= !self.receive(message, source_client);
+            }
+            if let Some(tx_id) = self.pending.pop() {
+                op(&tx_id, self.raw_txs.take(&tx_id));
+                counter += 1;
+                continue;
+            }
+            match self
+                .rx
+                .recv_timeout(Duration::from_millis(POLL_INTERVAL_MS as u64))
+            {
+                Ok(message) => self.deferred_gap |= !self.receive(message, source_client),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(self.thread_error()),
+            }
+        }
+        Ok(counter)
+    }
+
+    /// Blocks executing `op` for every tx received until `deadline` is reached. Unlike
+    /// `for_each`, the thread keeps listening so it can be called again.
+    pub fn for_each_until<F, R>(
+        &mut self,
+        deadline: Instant,
+        source_client: &Client,
+        op: F,
+        resync: R,
+    ) -> Result<usize>
+    where
+        F: Fn(&Txid, Option<Vec<u8>>),
+        R: Fn() -> Result<u64>,
+    {
+        let mut counter = 0;
+        while Instant::now() < deadline {
+            self.receive_all(source_client, &resync);
+            if let Some(tx_id) = self.pending.pop() {
+                op(&tx_id, self.raw_txs.take(&tx_id));
+                counter += 1;
+                continue;
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(timeout) {
+                Ok(message) => {
+                    if !self.receive(message, source_client) {
+                        self.resync(&resync);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(self.thread_error()),
+            }
+        }
+        Ok(counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Genesis block coinbase, as shown by rpc
+    const TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    fn body(label: u8, seq_num: Option<u64>) -> Vec<u8> {
+        let mut body = hex::decode(TXID).unwrap();
+        body.push(label);
+        if let Some(seq_num) = seq_num {
+            body.extend(seq_num.to_le_bytes());
+        }
+        body
+    }
+
+    fn message(topic: &str, body: Vec<u8>) -> Vec<Vec<u8>> {
+        vec![topic.as_bytes().to_vec(), body, 7u32.to_le_bytes().to_vec()]
+    }
+
+    #[test]
+    fn parses_tx_events_in_rpc_byte_order() {
+        let txid: Txid = TXID.parse().unwrap();
+        assert_eq!(
+            parse_message(&message("sequence", body(b'A', Some(42)))),
+            Ok(MempoolSequence::TxAdded { txid, seq_num: 42 })
+        );
+        assert_eq!(
+            parse_message(&message("sequence", body(b'R', Some(43)))),
+            Ok(MempoolSequence::TxRemoved { txid, seq_num: 43 })
+        );
+    }
+
+    #[test]
+    fn parses_block_events() {
+        let hash: BlockHash = TXID.parse().unwrap();
+        assert_eq!(
+            parse_message(&message("sequence", body(b'C', None))),
+            Ok(MempoolSequence::BlockConnection(hash))
+        );
+        assert_eq!(
+            parse_message(&message("sequence", body(b'D', None))),
+            Ok(MempoolSequence::BlockDisconnection(hash))
+        );
+    }
+
+    #[test]
+    fn rejects_short_bodies() {
+        assert_eq!(
+            parse_message(&message("sequence", vec![0; BLOCK_EVENT_SIZE - 1])),
+            Err(ParseError::TooShort {
+                len: BLOCK_EVENT_SIZE - 1,
+                expected: BLOCK_EVENT_SIZE
+            })
+        );
+        //A block event is long enough, a tx event needs the mempool sequence
+        let mut short = body(b'A', Some(1));
+        short.truncate(TX_EVENT_SIZE - 1);
+        assert_eq!(
+            parse_message(&message("sequence", short)),
+            Err(ParseError::TooShort {
+                len: TX_EVENT_SIZE - 1,
+                expected: TX_EVENT_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_labels() {
+        assert_eq!(
+            parse_message(&message("sequence", body(b'X', Some(1)))),
+            Err(ParseError::UnknownLabel(b'X'))
+        );
+    }
+
+    #[test]
+    fn rejects_other_topics_and_missing_bodies() {
+        assert_eq!(
+            parse_message(&message("rawtx", body(b'A', Some(1)))),
+            Err(ParseError::UnexpectedTopic("rawtx".to_string()))
+        );
+        assert_eq!(
+            parse_message(&[]),
+            Err(ParseError::UnexpectedTopic(String::new()))
+        );
+        assert_eq!(
+            parse_message(&[b"sequence".to_vec()]),
+            Err(ParseError::MissingBody)
+        );
+    }
+
+    #[test]
+    fn reads_message_counter() {
+        assert_eq!(
+            message_counter(&message("sequence", body(b'C', None))),
+            Some(7)
+        );
+        assert_eq!(message_counter(&[b"sequence".to_vec()]), None);
+    }
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    fn added(n: u8, seq_num: u64) -> MempoolSequence {
+        MempoolSequence::TxAdded {
+            txid: txid(n),
+            seq_num,
+        }
+    }
+
+    fn removed(n: u8, seq_num: u64) -> MempoolSequence {
+        MempoolSequence::TxRemoved {
+            txid: txid(n),
+            seq_num,
+        }
+    }
+
+    //Nothing listens there, blocks can't be asked for and are taken as empty.
+    fn unreachable_client() -> Client {
+        Client::new("http://127.0.0.1:1", bitcoincore_rpc::Auth::None).unwrap()
+    }
+
+    fn pending(snapshot_sequence: u64) -> Pending {
+        let mut pending = Pending::default();
+        pending.set_snapshot_sequence(snapshot_sequence);
+        pending
+    }
+
+    #[test]
+    fn in_order_events_have_no_gap() {
+        let client = unreachable_client();
+        let mut pending = pending(10);
+        //Already in the snapshot
+        assert!(pending.apply(added(1, 9), &client));
+        assert!(pending.apply(added(2, 10), &client));
+        assert!(pending.apply(added(3, 11), &client));
+        assert!(pending.apply(removed(2, 12), &client));
+        assert!(pending.apply(added(4, 13), &client));
+        assert_eq!(pending.pop(), Some(txid(3)));
+        assert_eq!(pending.pop(), Some(txid(4)));
+        assert_eq!(pending.pop(), None);
+    }
+
+    #[test]
+    fn detects_lost_events() {
+        let client = unreachable_client();
+        let mut pending = pending(10);
+        assert!(pending.apply(added(1, 10), &client));
+        assert!(!pending.apply(added(2, 12), &client));
+        assert!(pending.apply(added(3, 13), &client));
+    }
+
+    #[test]
+    fn tolerates_removals_before_their_block() {
+        let client = unreachable_client();
+        let hash = BlockHash::from_byte_array([9; 32]);
+        let mut pending = pending(10);
+        assert!(pending.apply(added(1, 10), &client));
+        //Confirmed txs take 11 and 13 silently, conflicts are notified before the block
+        assert!(pending.apply(removed(2, 12), &client));
+        assert!(pending.apply(removed(3, 14), &client));
+        assert!(pending.apply(MempoolSequence::BlockConnection(hash), &client));
+        assert!(pending.apply(added(4, 15), &client));
+    }
+
+    #[test]
+    fn removal_jump_without_block_is_a_gap() {
+        let client = unreachable_client();
+        let mut pending = pending(10);
+        assert!(pending.apply(added(1, 10), &client));
+        assert!(pending.apply(removed(2, 12), &client));
+        assert!(!pending.apply(added(3, 13), &client));
+        assert!(pending.apply(added(4, 14), &client));
+    }
+
+    #[test]
+    fn replaces_wildcard_bind_addresses() {
+        let host = Some("10.0.0.1".to_string());
+        assert_eq!(
+            connectable_address("tcp://0.0.0.0:28332", &host)
+                .unwrap()
+                .as_str(),
+            "tcp://10.0.0.1:28332"
+        );
+        assert_eq!(
+            connectable_address("tcp://127.0.0.1:28332", &None)
+                .unwrap()
+                .as_str(),
+            "tcp://127.0.0.1:28332"
+        );
+        assert!(connectable_address("tcp://*:28332", &None).is_err());
+    }
 }